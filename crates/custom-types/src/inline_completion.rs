@@ -0,0 +1,59 @@
+//! Types of the `textDocument/inlineCompletion` request introduced in LSP 3.18, which are not
+//! available in the version of `lsp_types` we depend on.
+
+use lsp_types::{Command, Range, TextDocumentPositionParams, WorkDoneProgressParams};
+use serde::{Deserialize, Serialize};
+
+/// Describes how an inline completion request was triggered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct InlineCompletionTriggerKind(i32);
+
+impl InlineCompletionTriggerKind {
+    /// Completion was triggered explicitly by a user gesture.
+    pub const INVOKED: InlineCompletionTriggerKind = InlineCompletionTriggerKind(1);
+    /// Completion was triggered automatically while editing.
+    pub const AUTOMATIC: InlineCompletionTriggerKind = InlineCompletionTriggerKind(2);
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedCompletionInfo {
+    pub range: Range,
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionContext {
+    pub trigger_kind: InlineCompletionTriggerKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selected_completion_info: Option<SelectedCompletionInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionParams {
+    #[serde(flatten)]
+    pub text_document_position: TextDocumentPositionParams,
+    #[serde(flatten)]
+    pub work_done_progress_params: WorkDoneProgressParams,
+    pub context: InlineCompletionContext,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineCompletionItem {
+    pub insert_text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InlineCompletionList {
+    pub items: Vec<InlineCompletionItem>,
+}
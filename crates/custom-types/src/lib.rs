@@ -1,2 +1,4 @@
+pub mod inline_completion;
 pub mod llm_ls;
+pub mod preset;
 pub mod request;
@@ -1,10 +1,12 @@
 use std::{fmt::Display, path::PathBuf};
 
-use lsp_types::TextDocumentPositionParams;
+use lsp_types::{PartialResultParams, ProgressToken, Range, TextDocumentPositionParams};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::{Map, Value};
 use uuid::Uuid;
 
+use crate::preset::Preset;
+
 const HF_INFERENCE_API_HOSTNAME: &str = "api-inference.huggingface.co";
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -56,19 +58,75 @@ where
     Option::deserialize(d).map(|b| b.unwrap_or_else(hf_default_url))
 }
 
+/// Window sizes are at least one line: an empty window would not hold the cursor.
+fn parse_window_size<'de, D>(d: D) -> std::result::Result<usize, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    usize::deserialize(d).map(|window_size| window_size.max(1))
+}
+
+/// Unset when there is no `backend` field, an error when the fields do not describe a valid
+/// backend rather than falling back to the default one, which could send code to a service the
+/// user did not configure.
+fn parse_backend_override<'de, D>(d: D) -> std::result::Result<Option<Backend>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let fields = Map::<String, Value>::deserialize(d)?;
+    if !fields.contains_key("backend") {
+        return Ok(None);
+    }
+    Backend::deserialize(Value::Object(fields))
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
 fn hf_default_url() -> String {
     format!("https://{HF_INFERENCE_API_HOSTNAME}")
 }
 
+fn anthropic_default_url() -> String {
+    "https://api.anthropic.com".to_owned()
+}
+
+/// Route of llama.cpp's server that completions are requested from
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlamaCppMode {
+    /// OpenAI compatible `/v1/completions` route, taking the prompt built by llm-ls
+    #[default]
+    Completions,
+    /// Native `/infill` route, taking the code around the cursor and the cross-file snippets
+    /// apart and adding the model's own FIM tokens
+    Infill,
+}
+
+fn codestral_default_url() -> String {
+    "https://codestral.mistral.ai".to_owned()
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase", tag = "backend")]
 pub enum Backend {
+    /// Anthropic's Messages API
+    Anthropic {
+        #[serde(default = "anthropic_default_url")]
+        url: String,
+    },
+    /// Mistral's FIM completions API, serving Codestral
+    Codestral {
+        #[serde(default = "codestral_default_url")]
+        url: String,
+    },
     HuggingFace {
         #[serde(default = "hf_default_url", deserialize_with = "parse_url")]
         url: String,
     },
     LlamaCpp {
         url: String,
+        #[serde(default)]
+        mode: LlamaCppMode,
     },
     Ollama {
         url: String,
@@ -76,6 +134,10 @@ pub enum Backend {
     OpenAi {
         url: String,
     },
+    /// OpenAI compatible chat completions API
+    OpenAiChat {
+        url: String,
+    },
     Tgi {
         url: String,
     },
@@ -97,23 +159,184 @@ impl Backend {
         }
     }
 
+    /// Name of the backend, as set in the `backend` field of the configuration
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Anthropic { .. } => "anthropic",
+            Self::Codestral { .. } => "codestral",
+            Self::HuggingFace { .. } => "huggingface",
+            Self::LlamaCpp { .. } => "llamacpp",
+            Self::Ollama { .. } => "ollama",
+            Self::OpenAi { .. } => "openai",
+            Self::OpenAiChat { .. } => "openaichat",
+            Self::Tgi { .. } => "tgi",
+        }
+    }
+
+    /// Mode of the backend, for backends that can be queried in several ways
+    pub fn mode(&self) -> Option<&'static str> {
+        match self {
+            Self::LlamaCpp {
+                mode: LlamaCppMode::Infill,
+                ..
+            } => Some("infill"),
+            _ => None,
+        }
+    }
+
     pub fn url(self) -> String {
         match self {
+            Self::Anthropic { url } => url,
+            Self::Codestral { url } => url,
             Self::HuggingFace { url } => url,
-            Self::LlamaCpp { url } => url,
+            Self::LlamaCpp { url, .. } => url,
             Self::Ollama { url } => url,
             Self::OpenAi { url } => url,
+            Self::OpenAiChat { url } => url,
             Self::Tgi { url } => url,
         }
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct FimParams {
     pub enabled: bool,
     pub prefix: String,
     pub middle: String,
     pub suffix: String,
+    /// Layout of the prompt, replacing `prefix`, `middle` and `suffix` when set. The `{prefix}`,
+    /// `{suffix}`, `{filename}`, `{repo}` and `{context}` placeholders are substituted with the
+    /// code before and after the cursor, the path of the document, the name of the workspace
+    /// folder and the cross-file context
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Token preceding the path of each cross-file snippet instead of a comment, e.g.
+    /// `<file_sep>`
+    #[serde(default)]
+    pub file_separator: Option<String>,
+    /// Share of the context window given to the code before the cursor, the rest going to the
+    /// code after it. Budget left unused by one side goes to the other
+    #[serde(default = "default_prefix_ratio")]
+    pub prefix_ratio: f32,
+    /// Maximum number of lines after the cursor's line included in the prompt
+    #[serde(default)]
+    pub max_suffix_lines: Option<usize>,
+}
+
+fn default_prefix_ratio() -> f32 {
+    0.5
+}
+
+/// Selection of snippets from the other open documents, prepended to the prompt
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CrossFileContextParams {
+    pub enabled: bool,
+    /// Maximum number of snippets added to the prompt, at most one per document
+    pub max_snippets: usize,
+    /// Number of lines of a snippet, as well as the number of lines before the cursor snippets
+    /// are compared to
+    #[serde(deserialize_with = "parse_window_size")]
+    pub window_size: usize,
+    /// Maximum number of tokens used by the snippets, capped to half the context window
+    pub max_tokens: usize,
+}
+
+impl Default for CrossFileContextParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_snippets: 4,
+            window_size: 20,
+            max_tokens: 512,
+        }
+    }
+}
+
+/// Offline index of the files of the workspace folders, whose chunks most relevant to the code
+/// around the cursor are prepended to the prompt
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct WorkspaceIndexParams {
+    pub enabled: bool,
+    /// Number of chunks added to the prompt, sharing the cross-file context token budget
+    pub top_k: usize,
+    /// Maximum number of lines of a chunk
+    pub max_chunk_lines: usize,
+    /// Files bigger than this size in bytes are not indexed
+    pub max_file_size: u64,
+}
+
+impl Default for WorkspaceIndexParams {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            top_k: 3,
+            max_chunk_lines: 40,
+            max_file_size: 512 * 1024,
+        }
+    }
+}
+
+/// Syntax nodes inside which no completion is requested, for the languages having completion
+/// rules
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SuppressParams {
+    pub strings: bool,
+    pub comments: bool,
+}
+
+impl Default for SuppressParams {
+    fn default() -> Self {
+        Self {
+            strings: true,
+            comments: false,
+        }
+    }
+}
+
+/// Messages sent to chat backends, wrapping the prompt built from the code around the cursor
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChatParams {
+    /// System message, left out when empty
+    pub system: String,
+    /// User message, in which `{prompt}` is replaced with the prompt
+    pub user: String,
+}
+
+impl Default for ChatParams {
+    fn default() -> Self {
+        Self {
+            system: "You are a code completion engine. Reply with the code to insert at the \
+                     cursor only, without any explanation."
+                .to_owned(),
+            user: "{prompt}".to_owned(),
+        }
+    }
+}
+
+/// In memory cache of the generations, looked up before querying the backend
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CompletionCacheParams {
+    pub enabled: bool,
+    /// Maximum number of cached requests, the least recently used being evicted first
+    pub capacity: usize,
+    /// Time after which a cached generation is discarded
+    pub ttl_ms: u64,
+}
+
+impl Default for CompletionCacheParams {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            capacity: 128,
+            ttl_ms: 5 * 60 * 1000,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -132,36 +355,277 @@ pub enum TokenizerConfig {
     },
 }
 
+/// Server side configuration, set through `initializationOptions` and
+/// `workspace/didChangeConfiguration`
 #[derive(Clone, Debug, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-pub struct GetCompletionsParams {
-    #[serde(flatten)]
-    pub text_document_position: TextDocumentPositionParams,
-    #[serde(default)]
-    #[serde(deserialize_with = "parse_ide")]
-    pub ide: Ide,
+#[serde(default, rename_all = "camelCase")]
+pub struct LlmLsConfig {
     pub fim: FimParams,
     pub api_token: Option<String>,
     pub model: String,
     #[serde(flatten)]
     pub backend: Backend,
     pub tokens_to_clear: Vec<String>,
+    /// Sequences on which the backend stops generating
+    pub stop_tokens: Vec<String>,
     pub tokenizer_config: Option<TokenizerConfig>,
     pub context_window: usize,
     pub tls_skip_verify_insecure: bool,
-    #[serde(default)]
+    /// Fields added to the body of the requests, in the format of the backend's API
     pub request_body: Map<String, Value>,
-    #[serde(default)]
     pub disable_url_path_completion: bool,
+    /// Time to wait before querying the backend, during which the request is dropped if a newer
+    /// one for the same document comes in
+    pub debounce_ms: Option<u64>,
+    pub cross_file_context: CrossFileContextParams,
+    pub workspace_index: WorkspaceIndexParams,
+    /// Number of completions to generate, ranked by likelihood when the backend returns log
+    /// probabilities
+    pub num_candidates: usize,
+    /// Completions are cut before their first line whose confidence is below this threshold,
+    /// and dropped when it is their first line
+    pub min_confidence: Option<f32>,
+    pub cache: CompletionCacheParams,
+    pub suppress: SuppressParams,
+    pub chat: ChatParams,
+}
+
+impl Default for LlmLsConfig {
+    fn default() -> Self {
+        Self {
+            fim: FimParams {
+                enabled: true,
+                prefix: "<fim_prefix>".to_owned(),
+                middle: "<fim_middle>".to_owned(),
+                suffix: "<fim_suffix>".to_owned(),
+                template: None,
+                file_separator: None,
+                prefix_ratio: default_prefix_ratio(),
+                max_suffix_lines: None,
+            },
+            api_token: None,
+            model: "bigcode/starcoder2-15b".to_owned(),
+            backend: Backend::default(),
+            tokens_to_clear: vec!["<|endoftext|>".to_owned()],
+            stop_tokens: vec![],
+            tokenizer_config: None,
+            context_window: 1024,
+            tls_skip_verify_insecure: false,
+            request_body: Map::new(),
+            disable_url_path_completion: false,
+            debounce_ms: None,
+            cross_file_context: CrossFileContextParams::default(),
+            workspace_index: WorkspaceIndexParams::default(),
+            num_candidates: 1,
+            min_confidence: None,
+            cache: CompletionCacheParams::default(),
+            suppress: SuppressParams::default(),
+            chat: ChatParams::default(),
+        }
+    }
+}
+
+impl LlmLsConfig {
+    /// Replaces every field of the configuration that is set in `overrides`. The fields filled by
+    /// its preset, if any, are replaced first so that the ones set explicitly take precedence.
+    pub fn apply(&mut self, overrides: LlmLsConfigOverrides) {
+        if let Some(preset) = overrides.preset {
+            preset.apply_fim(&mut self.fim);
+            self.apply(preset.overrides());
+        }
+        if let Some(fim) = overrides.fim {
+            self.fim = fim;
+        }
+        if let Some(api_token) = overrides.api_token {
+            self.api_token = Some(api_token);
+        }
+        if let Some(model) = overrides.model {
+            self.model = model;
+        }
+        if let Some(backend) = overrides.backend {
+            self.backend = backend;
+        }
+        if let Some(tokens_to_clear) = overrides.tokens_to_clear {
+            self.tokens_to_clear = tokens_to_clear;
+        }
+        if let Some(stop_tokens) = overrides.stop_tokens {
+            self.stop_tokens = stop_tokens;
+        }
+        if let Some(tokenizer_config) = overrides.tokenizer_config {
+            self.tokenizer_config = Some(tokenizer_config);
+        }
+        if let Some(context_window) = overrides.context_window {
+            self.context_window = context_window;
+        }
+        if let Some(tls_skip_verify_insecure) = overrides.tls_skip_verify_insecure {
+            self.tls_skip_verify_insecure = tls_skip_verify_insecure;
+        }
+        if let Some(request_body) = overrides.request_body {
+            self.request_body = request_body;
+        }
+        if let Some(disable_url_path_completion) = overrides.disable_url_path_completion {
+            self.disable_url_path_completion = disable_url_path_completion;
+        }
+        if let Some(debounce_ms) = overrides.debounce_ms {
+            self.debounce_ms = Some(debounce_ms);
+        }
+        if let Some(cross_file_context) = overrides.cross_file_context {
+            self.cross_file_context = cross_file_context;
+        }
+        if let Some(workspace_index) = overrides.workspace_index {
+            self.workspace_index = workspace_index;
+        }
+        if let Some(num_candidates) = overrides.num_candidates {
+            self.num_candidates = num_candidates;
+        }
+        if let Some(min_confidence) = overrides.min_confidence {
+            self.min_confidence = Some(min_confidence);
+        }
+        if let Some(cache) = overrides.cache {
+            self.cache = cache;
+        }
+        if let Some(suppress) = overrides.suppress {
+            self.suppress = suppress;
+        }
+        if let Some(chat) = overrides.chat {
+            self.chat = chat;
+        }
+    }
+}
+
+/// Partial [`LlmLsConfig`], every field that is set takes precedence over the configuration it
+/// is applied to.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LlmLsConfigOverrides {
+    /// Fills the FIM tokens, `stopTokens` and `tokensToClear` for a family of models, as well as
+    /// the repository level `fim.template` and `fim.fileSeparator` when unset
+    pub preset: Option<Preset>,
+    pub fim: Option<FimParams>,
+    pub api_token: Option<String>,
+    pub model: Option<String>,
+    #[serde(flatten, deserialize_with = "parse_backend_override")]
+    pub backend: Option<Backend>,
+    pub tokens_to_clear: Option<Vec<String>>,
+    pub stop_tokens: Option<Vec<String>>,
+    pub tokenizer_config: Option<TokenizerConfig>,
+    pub context_window: Option<usize>,
+    pub tls_skip_verify_insecure: Option<bool>,
+    pub request_body: Option<Map<String, Value>>,
+    pub disable_url_path_completion: Option<bool>,
+    pub debounce_ms: Option<u64>,
+    pub cross_file_context: Option<CrossFileContextParams>,
+    pub workspace_index: Option<WorkspaceIndexParams>,
+    pub num_candidates: Option<usize>,
+    pub min_confidence: Option<f32>,
+    pub cache: Option<CompletionCacheParams>,
+    pub suppress: Option<SuppressParams>,
+    pub chat: Option<ChatParams>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GetCompletionsParams {
+    #[serde(flatten)]
+    pub text_document_position: TextDocumentPositionParams,
+    /// When set, the generated text is streamed back to the client as `$/progress`
+    /// notifications carrying a [`GetCompletionsResult`] with the text generated so far
+    #[serde(flatten)]
+    pub partial_result_params: PartialResultParams,
+    #[serde(default)]
+    #[serde(deserialize_with = "parse_ide")]
+    pub ide: Ide,
+    /// Overrides of the server side configuration for this request only
+    #[serde(flatten)]
+    pub config: LlmLsConfigOverrides,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Completion {
     pub generated_text: String,
+    /// Text of the document the completion replaces, when it ends with the text following the
+    /// cursor. The completion is inserted at the cursor when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+    /// Geometric mean of the probabilities of the generated tokens, when the backend returns
+    /// them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionType {
+    SingleLine,
+    MultiLine,
+}
+
+impl Display for CompletionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompletionType::SingleLine => write!(f, "single_line"),
+            CompletionType::MultiLine => write!(f, "multi_line"),
+        }
+    }
+}
+
+/// Why no completion was requested from the backend, or none was returned
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SkipReason {
+    DocumentNotFound,
+    EmptyDocument,
+    /// The document matches an `exclude` pattern of its project configuration
+    Excluded,
+    /// The cursor is followed by a word character
+    MidWord,
+    InsideComment,
+    InsideString,
+    RateLimited,
+    BackendError {
+        message: String,
+    },
+}
+
+/// Number of tokens of each part of the prompt, estimated when no tokenizer is configured
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct PromptTokenCounts {
+    /// Code before the cursor
+    pub prefix: usize,
+    /// Code after the cursor
+    pub suffix: usize,
+    /// Snippets of the other files
+    pub context: usize,
+    /// Whole prompt, including the FIM tokens
+    pub total: usize,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct GetCompletionsResult {
     pub request_id: Uuid,
     pub completions: Vec<Completion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skip_reason: Option<SkipReason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_type: Option<CompletionType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<PromptTokenCounts>,
+}
+
+impl GetCompletionsResult {
+    pub fn skipped(request_id: Uuid, skip_reason: SkipReason) -> Self {
+        Self {
+            request_id,
+            completions: vec![],
+            skip_reason: Some(skip_reason),
+            completion_type: None,
+            prompt_tokens: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GetCompletionsProgressParams {
+    pub token: ProgressToken,
+    pub value: GetCompletionsResult,
 }
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+
+use crate::llm_ls::{FimParams, LlmLsConfigOverrides};
+
+/// Prompt format and special tokens of a family of models
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Preset {
+    #[serde(rename = "starcoder")]
+    StarCoder,
+    #[serde(rename = "starcoder2")]
+    StarCoder2,
+    #[serde(rename = "codellama")]
+    CodeLlama,
+    #[serde(rename = "deepseek-coder")]
+    DeepSeekCoder,
+    #[serde(rename = "qwen-coder")]
+    QwenCoder,
+    #[serde(rename = "codegemma")]
+    CodeGemma,
+    #[serde(rename = "stable-code")]
+    StableCode,
+}
+
+impl Preset {
+    const ALL: [Self; 7] = [
+        Self::StarCoder,
+        Self::StarCoder2,
+        Self::CodeLlama,
+        Self::DeepSeekCoder,
+        Self::QwenCoder,
+        Self::CodeGemma,
+        Self::StableCode,
+    ];
+
+    /// FIM prefix, middle and suffix tokens of the model family
+    pub fn fim_tokens(self) -> [&'static str; 3] {
+        match self {
+            Self::StarCoder | Self::StarCoder2 | Self::StableCode => {
+                ["<fim_prefix>", "<fim_middle>", "<fim_suffix>"]
+            }
+            Self::CodeLlama => ["<PRE> ", " <MID>", " <SUF>"],
+            Self::DeepSeekCoder => ["<｜fim▁begin｜>", "<｜fim▁end｜>", "<｜fim▁hole｜>"],
+            Self::QwenCoder | Self::CodeGemma => {
+                ["<|fim_prefix|>", "<|fim_middle|>", "<|fim_suffix|>"]
+            }
+        }
+    }
+
+    /// Layout of repository level prompts, for the models trained on them
+    pub fn template(self) -> Option<&'static str> {
+        match self {
+            Self::StarCoder2 => Some(
+                "<repo_name>{repo}{context}<file_sep>{filename}\n<fim_prefix>{prefix}<fim_suffix>{suffix}<fim_middle>",
+            ),
+            Self::QwenCoder => Some(
+                "<|repo_name|>{repo}\n{context}<|file_sep|>{filename}\n<|fim_prefix|>{prefix}<|fim_suffix|>{suffix}<|fim_middle|>",
+            ),
+            _ => None,
+        }
+    }
+
+    /// Token preceding the path of each file of repository level prompts
+    pub fn file_separator(self) -> Option<&'static str> {
+        match self {
+            Self::StarCoder2 => Some("<file_sep>"),
+            Self::QwenCoder => Some("<|file_sep|>"),
+            _ => None,
+        }
+    }
+
+    /// Sets the FIM tokens of the model family, leaving the other fields of `fim` as they are.
+    /// The repository level template and file separator are only replaced when unset or set by
+    /// a preset, so that the ones set explicitly in another configuration layer are kept.
+    pub fn apply_fim(self, fim: &mut FimParams) {
+        let [prefix, middle, suffix] = self.fim_tokens();
+        fim.prefix = prefix.to_owned();
+        fim.middle = middle.to_owned();
+        fim.suffix = suffix.to_owned();
+        let from_preset = |value: Option<&str>, preset_value: fn(Self) -> Option<&'static str>| {
+            value.is_none_or(|value| {
+                Self::ALL
+                    .into_iter()
+                    .any(|preset| preset_value(preset) == Some(value))
+            })
+        };
+        if from_preset(fim.template.as_deref(), Self::template) {
+            fim.template = self.template().map(str::to_owned);
+        }
+        if from_preset(fim.file_separator.as_deref(), Self::file_separator) {
+            fim.file_separator = self.file_separator().map(str::to_owned);
+        }
+    }
+
+    /// Tokens on which the generation should stop
+    pub fn stop_tokens(self) -> &'static [&'static str] {
+        match self {
+            Self::StarCoder | Self::StableCode => &["<|endoftext|>"],
+            Self::StarCoder2 => &["<|endoftext|>", "<file_sep>"],
+            Self::CodeLlama => &["<EOT>"],
+            Self::DeepSeekCoder => &["<｜end▁of▁sentence｜>"],
+            Self::QwenCoder => &["<|endoftext|>", "<|file_sep|>", "<|fim_pad|>"],
+            Self::CodeGemma => &["<|file_separator|>", "<|fim_prefix|>", "<|fim_suffix|>"],
+        }
+    }
+
+    /// Tokens the backends may leave at the end of the generated text
+    pub fn tokens_to_clear(self) -> &'static [&'static str] {
+        match self {
+            Self::StarCoder | Self::StableCode => &["<|endoftext|>"],
+            Self::StarCoder2 => &["<|endoftext|>", "<file_sep>"],
+            Self::CodeLlama => &["<EOT>"],
+            Self::DeepSeekCoder => &["<｜end▁of▁sentence｜>"],
+            Self::QwenCoder => &["<|endoftext|>", "<|file_sep|>"],
+            Self::CodeGemma => &["<|file_separator|>"],
+        }
+    }
+
+    /// Configuration fields set by the preset other than the FIM ones, see [`Preset::apply_fim`]
+    pub fn overrides(self) -> LlmLsConfigOverrides {
+        let to_owned = |tokens: &[&str]| tokens.iter().map(|&t| t.to_owned()).collect();
+        LlmLsConfigOverrides {
+            stop_tokens: Some(to_owned(self.stop_tokens())),
+            tokens_to_clear: Some(to_owned(self.tokens_to_clear())),
+            ..Default::default()
+        }
+    }
+}
@@ -1,7 +1,9 @@
-use lsp_types::request::Request;
+use lsp_types::{notification::Notification, request::Request};
 
+use crate::inline_completion::{InlineCompletionList, InlineCompletionParams};
 use crate::llm_ls::{
-    AcceptCompletionParams, GetCompletionsParams, GetCompletionsResult, RejectCompletionParams,
+    AcceptCompletionParams, GetCompletionsParams, GetCompletionsProgressParams,
+    GetCompletionsResult, RejectCompletionParams,
 };
 
 #[derive(Debug)]
@@ -13,6 +15,16 @@ impl Request for GetCompletions {
     const METHOD: &'static str = "llm-ls/getCompletions";
 }
 
+/// Partial result of a [`GetCompletions`] request, sent when the client provided a
+/// `partialResultToken`
+#[derive(Debug)]
+pub enum GetCompletionsProgress {}
+
+impl Notification for GetCompletionsProgress {
+    type Params = GetCompletionsProgressParams;
+    const METHOD: &'static str = "$/progress";
+}
+
 #[derive(Debug)]
 pub enum AcceptCompletion {}
 
@@ -30,3 +42,12 @@ impl Request for RejectCompletion {
     type Result = ();
     const METHOD: &'static str = "llm-ls/rejectCompletion";
 }
+
+#[derive(Debug)]
+pub enum InlineCompletion {}
+
+impl Request for InlineCompletion {
+    type Params = InlineCompletionParams;
+    type Result = Option<InlineCompletionList>;
+    const METHOD: &'static str = "textDocument/inlineCompletion";
+}
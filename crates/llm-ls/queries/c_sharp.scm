@@ -0,0 +1,14 @@
+(comment) @comment
+[
+  (string_literal)
+  (verbatim_string_literal)
+  (interpolated_string_expression)
+  (character_literal)
+] @string
+
+(argument_list) @arguments
+
+[
+  (block "{" @block_opener)
+  (declaration_list "{" @block_opener)
+]
@@ -0,0 +1,11 @@
+(comment) @comment
+[(string_literal) (raw_string_literal) (char_literal)] @string
+
+(argument_list) @arguments
+
+[
+  (compound_statement "{" @block_opener)
+  (field_declaration_list "{" @block_opener)
+  (enumerator_list "{" @block_opener)
+  (declaration_list "{" @block_opener)
+]
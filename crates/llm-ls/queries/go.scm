@@ -0,0 +1,14 @@
+(comment) @comment
+[
+  (interpreted_string_literal)
+  (raw_string_literal)
+  (rune_literal)
+] @string
+
+(argument_list) @arguments
+
+[
+  (block "{" @block_opener)
+  (field_declaration_list "{" @block_opener)
+  (ERROR "{" @block_opener)
+]
@@ -0,0 +1,13 @@
+[(line_comment) (block_comment)] @comment
+[(string_literal) (character_literal)] @string
+
+(argument_list) @arguments
+
+[
+  (block "{" @block_opener)
+  (class_body "{" @block_opener)
+  (interface_body "{" @block_opener)
+  (enum_body "{" @block_opener)
+  (constructor_body "{" @block_opener)
+  (switch_block "{" @block_opener)
+]
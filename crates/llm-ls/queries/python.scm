@@ -0,0 +1,18 @@
+(comment) @comment
+(string) @string
+
+(argument_list) @arguments
+
+[
+  (function_definition ":" @block_opener)
+  (class_definition ":" @block_opener)
+  (if_statement ":" @block_opener)
+  (elif_clause ":" @block_opener)
+  (else_clause ":" @block_opener)
+  (for_statement ":" @block_opener)
+  (while_statement ":" @block_opener)
+  (try_statement ":" @block_opener)
+  (except_clause ":" @block_opener)
+  (finally_clause ":" @block_opener)
+  (with_statement ":" @block_opener)
+]
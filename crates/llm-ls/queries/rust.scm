@@ -0,0 +1,13 @@
+[(line_comment) (block_comment)] @comment
+[(string_literal) (raw_string_literal) (char_literal)] @string
+
+(arguments) @arguments
+
+[
+  (block "{" @block_opener)
+  (declaration_list "{" @block_opener)
+  (field_declaration_list "{" @block_opener)
+  (enum_variant_list "{" @block_opener)
+  (match_block "{" @block_opener)
+  (ERROR "{" @block_opener)
+]
@@ -0,0 +1,11 @@
+(comment) @comment
+[(string) (template_string)] @string
+
+(arguments) @arguments
+
+[
+  (statement_block "{" @block_opener)
+  (class_body "{" @block_opener)
+  (switch_body "{" @block_opener)
+  (enum_body "{" @block_opener)
+]
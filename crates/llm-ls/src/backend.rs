@@ -1,11 +1,16 @@
-use super::{Generation, NAME, VERSION};
-use custom_types::llm_ls::{Backend, Ide};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, USER_AGENT};
+use super::{Generation, Token, NAME, VERSION};
+use custom_types::llm_ls::{Backend, ChatParams, Ide};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, USER_AGENT};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
+use std::collections::HashMap;
 use std::fmt::Display;
+use tracing::warn;
 
+use crate::context::Snippet;
 use crate::error::{Error, Result};
+use crate::prompt::PromptLayout;
 
 #[derive(Debug, Deserialize)]
 pub struct APIError {
@@ -24,15 +29,94 @@ impl Display for APIError {
     }
 }
 
+/// Keeps the tokens of a generation when every one of them has a log probability.
+fn collect_tokens(tokens: impl IntoIterator<Item = (String, Option<f32>)>) -> Option<Vec<Token>> {
+    tokens
+        .into_iter()
+        .map(|(text, logprob)| {
+            Some(Token {
+                text,
+                logprob: logprob?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|tokens| !tokens.is_empty())
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiToken {
+    text: String,
+    logprob: Option<f32>,
+    #[serde(default)]
+    special: bool,
+}
+
+/// Special tokens are left out of the generated text
+fn collect_tgi_tokens(tokens: Vec<TgiToken>) -> Option<Vec<Token>> {
+    collect_tokens(
+        tokens
+            .into_iter()
+            .filter(|token| !token.special)
+            .map(|token| (token.text, token.logprob)),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiBestOfSequence {
+    generated_text: String,
+    tokens: Vec<TgiToken>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiDetails {
+    tokens: Vec<TgiToken>,
+    #[serde(default)]
+    best_of_sequences: Vec<TgiBestOfSequence>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TgiGeneration {
+    generated_text: String,
+    details: Option<TgiDetails>,
+}
+
+impl TgiGeneration {
+    /// Returns the generated sequence, followed by the other sequences generated when using
+    /// `best_of`, if any.
+    fn into_generations(self) -> Vec<Generation> {
+        let Some(details) = self.details else {
+            return vec![Generation {
+                generated_text: self.generated_text,
+                tokens: None,
+            }];
+        };
+        let mut generations = vec![Generation {
+            generated_text: self.generated_text,
+            tokens: collect_tgi_tokens(details.tokens),
+        }];
+        generations.extend(
+            details
+                .best_of_sequences
+                .into_iter()
+                .map(|sequence| Generation {
+                    generated_text: sequence.generated_text,
+                    tokens: collect_tgi_tokens(sequence.tokens),
+                }),
+        );
+        generations
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub(crate) enum APIResponse {
-    Generation(Generation),
-    Generations(Vec<Generation>),
+    Generation(TgiGeneration),
+    Generations(Vec<TgiGeneration>),
     Error(APIError),
 }
 
-fn build_tgi_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+/// Headers sent to every backend by default: the user agent and, if any, the API token.
+pub(crate) fn build_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
     let mut headers = HeaderMap::new();
     let user_agent = format!("{NAME}/{VERSION}; rust/unknown; ide/{ide:?}");
     headers.insert(USER_AGENT, HeaderValue::from_str(&user_agent)?);
@@ -49,33 +133,106 @@ fn build_tgi_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap>
 
 fn parse_tgi_text(text: &str) -> Result<Vec<Generation>> {
     match serde_json::from_str(text)? {
-        APIResponse::Generation(gen) => Ok(vec![gen]),
+        APIResponse::Generation(gen) => Ok(gen.into_generations()),
         APIResponse::Generations(_) => Err(Error::InvalidBackend),
         APIResponse::Error(err) => Err(Error::Tgi(err)),
     }
 }
 
-fn build_api_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
-    build_tgi_headers(api_token, ide)
+#[derive(Debug, Deserialize)]
+struct TgiStreamToken {
+    text: String,
+    special: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TgiStreamResponse {
+    token: TgiStreamToken,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TgiStreamAPIResponse {
+    Token(TgiStreamResponse),
+    Error(APIError),
+}
+
+fn parse_tgi_stream_chunk(data: &str) -> Result<StreamChunk> {
+    match serde_json::from_str(data)? {
+        TgiStreamAPIResponse::Token(res) if res.token.special => {
+            Ok(StreamChunk::Text(String::new()))
+        }
+        TgiStreamAPIResponse::Token(res) => Ok(StreamChunk::Text(res.token.text)),
+        TgiStreamAPIResponse::Error(err) => Err(Error::Tgi(err)),
+    }
+}
+
+fn parse_api_stream_chunk(data: &str) -> Result<StreamChunk> {
+    parse_tgi_stream_chunk(data).map_err(|err| match err {
+        Error::Tgi(err) => Error::InferenceApi(err),
+        err => err,
+    })
 }
 
 fn parse_api_text(text: &str) -> Result<Vec<Generation>> {
     match serde_json::from_str(text)? {
-        APIResponse::Generation(gen) => Ok(vec![gen]),
-        APIResponse::Generations(gens) => Ok(gens),
+        APIResponse::Generation(gen) => Ok(gen.into_generations()),
+        APIResponse::Generations(gens) => Ok(gens
+            .into_iter()
+            .flat_map(TgiGeneration::into_generations)
+            .collect()),
         APIResponse::Error(err) => Err(Error::InferenceApi(err)),
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct ContentLogprob {
+    token: String,
+    logprob: Option<f32>,
+}
+
+/// Log probabilities in the format of OpenAI's completions API, or of its chat completions API
+/// as returned by recent versions of llama.cpp
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum CompletionLogprobs {
+    Tokens {
+        tokens: Vec<String>,
+        token_logprobs: Vec<Option<f32>>,
+    },
+    Content {
+        content: Vec<ContentLogprob>,
+    },
+}
+
+impl CompletionLogprobs {
+    fn into_tokens(self) -> Option<Vec<Token>> {
+        match self {
+            Self::Tokens {
+                tokens,
+                token_logprobs,
+            } => collect_tokens(tokens.into_iter().zip(token_logprobs)),
+            Self::Content { content } => collect_tokens(
+                content
+                    .into_iter()
+                    .map(|logprob| (logprob.token, logprob.logprob)),
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct LlamaCppGenerationChoice {
     text: String,
+    #[serde(default)]
+    logprobs: Option<CompletionLogprobs>,
 }
 
 impl From<LlamaCppGenerationChoice> for Generation {
     fn from(value: LlamaCppGenerationChoice) -> Self {
         Generation {
             generated_text: value.text,
+            tokens: value.logprobs.and_then(CompletionLogprobs::into_tokens),
         }
     }
 }
@@ -85,26 +242,122 @@ struct LlamaCppGeneration {
     choices: Vec<LlamaCppGenerationChoice>,
 }
 
+/// Errors of llama.cpp's server, depending on its version a plain message or in the format of
+/// OpenAI's API
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
-enum LlamaCppAPIResponse {
-    Generation(LlamaCppGeneration),
-    Error(APIError),
+pub enum LlamaCppError {
+    Message(APIError),
+    OpenAI(OpenAIError),
+}
+
+impl Display for LlamaCppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Message(err) => err.fmt(f),
+            Self::OpenAI(err) => err.fmt(f),
+        }
+    }
 }
 
-fn build_llamacpp_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
-    build_api_headers(api_token, ide)
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LlamaCppAPIResponse {
+    Generation(LlamaCppGeneration),
+    Error(LlamaCppError),
 }
 
 fn parse_llamacpp_text(text: &str) -> Result<Vec<Generation>> {
     match serde_json::from_str(text)? {
         LlamaCppAPIResponse::Generation(completion) => {
-            Ok(completion.choices.into_iter().map(|x| x.into()).collect()) 
-        },
+            Ok(completion.choices.into_iter().map(|x| x.into()).collect())
+        }
         LlamaCppAPIResponse::Error(err) => Err(Error::LlamaCpp(err)),
     }
 }
 
+fn parse_llamacpp_stream_chunk(data: &str) -> Result<StreamChunk> {
+    if data == "[DONE]" {
+        return Ok(StreamChunk::Done);
+    }
+    match serde_json::from_str(data)? {
+        LlamaCppAPIResponse::Generation(completion) => Ok(StreamChunk::Text(
+            completion.choices.into_iter().map(|x| x.text).collect(),
+        )),
+        LlamaCppAPIResponse::Error(err) => Err(Error::LlamaCpp(err)),
+    }
+}
+
+/// Probability of a generated token, `content` being the name of its text in older versions of
+/// llama.cpp's server, which did not return log probabilities
+#[derive(Debug, Deserialize)]
+struct LlamaCppTokenProbability {
+    #[serde(alias = "content")]
+    token: String,
+    #[serde(default)]
+    logprob: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LlamaCppInfillGeneration {
+    content: String,
+    #[serde(default)]
+    completion_probabilities: Option<Vec<LlamaCppTokenProbability>>,
+}
+
+impl From<LlamaCppInfillGeneration> for Generation {
+    fn from(value: LlamaCppInfillGeneration) -> Self {
+        Generation {
+            generated_text: value.content,
+            tokens: value.completion_probabilities.and_then(|probabilities| {
+                collect_tokens(
+                    probabilities
+                        .into_iter()
+                        .map(|probability| (probability.token, probability.logprob)),
+                )
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LlamaCppInfillAPIResponse {
+    Generation(LlamaCppInfillGeneration),
+    Error(LlamaCppError),
+}
+
+fn parse_llamacpp_infill_text(text: &str) -> Result<Vec<Generation>> {
+    match serde_json::from_str(text)? {
+        LlamaCppInfillAPIResponse::Generation(gen) => Ok(vec![gen.into()]),
+        LlamaCppInfillAPIResponse::Error(err) => Err(Error::LlamaCpp(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LlamaCppInfillStreamResponse {
+    content: String,
+    #[serde(default)]
+    stop: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LlamaCppInfillStreamAPIResponse {
+    Generation(LlamaCppInfillStreamResponse),
+    Error(LlamaCppError),
+}
+
+fn parse_llamacpp_infill_stream_chunk(data: &str) -> Result<StreamChunk> {
+    match serde_json::from_str(data)? {
+        LlamaCppInfillStreamAPIResponse::Generation(gen) if gen.stop && gen.content.is_empty() => {
+            Ok(StreamChunk::Done)
+        }
+        LlamaCppInfillStreamAPIResponse::Generation(gen) => Ok(StreamChunk::Text(gen.content)),
+        LlamaCppInfillStreamAPIResponse::Error(err) => Err(Error::LlamaCpp(err)),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct OllamaGeneration {
     response: String,
@@ -114,6 +367,7 @@ impl From<OllamaGeneration> for Generation {
     fn from(value: OllamaGeneration) -> Self {
         Generation {
             generated_text: value.response,
+            tokens: None,
         }
     }
 }
@@ -125,10 +379,6 @@ enum OllamaAPIResponse {
     Error(APIError),
 }
 
-fn build_ollama_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
-    build_api_headers(api_token, ide)
-}
-
 fn parse_ollama_text(text: &str) -> Result<Vec<Generation>> {
     match serde_json::from_str(text)? {
         OllamaAPIResponse::Generation(gen) => Ok(vec![gen.into()]),
@@ -136,15 +386,41 @@ fn parse_ollama_text(text: &str) -> Result<Vec<Generation>> {
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct OllamaStreamResponse {
+    response: String,
+    done: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OllamaStreamAPIResponse {
+    Generation(OllamaStreamResponse),
+    Error(APIError),
+}
+
+fn parse_ollama_stream_chunk(data: &str) -> Result<StreamChunk> {
+    match serde_json::from_str(data)? {
+        OllamaStreamAPIResponse::Generation(gen) if gen.done && gen.response.is_empty() => {
+            Ok(StreamChunk::Done)
+        }
+        OllamaStreamAPIResponse::Generation(gen) => Ok(StreamChunk::Text(gen.response)),
+        OllamaStreamAPIResponse::Error(err) => Err(Error::Ollama(err)),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct OpenAIGenerationChoice {
     text: String,
+    #[serde(default)]
+    logprobs: Option<CompletionLogprobs>,
 }
 
 impl From<OpenAIGenerationChoice> for Generation {
     fn from(value: OpenAIGenerationChoice) -> Self {
         Generation {
             generated_text: value.text,
+            tokens: value.logprobs.and_then(CompletionLogprobs::into_tokens),
         }
     }
 }
@@ -171,24 +447,47 @@ impl Display for OpenAIErrorLoc {
 }
 
 #[derive(Debug, Deserialize)]
-struct OpenAIErrorDetail {
+pub struct OpenAIErrorDetail {
     loc: OpenAIErrorLoc,
     msg: String,
     r#type: String,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct OpenAIError {
-    detail: Vec<OpenAIErrorDetail>,
+pub struct OpenAIErrorMessage {
+    message: String,
+    r#type: Option<String>,
+    code: Option<OpenAIErrorLoc>,
+}
+
+/// Validation errors of OpenAI compatible servers, or the error envelope of OpenAI's API
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OpenAIError {
+    Detail { detail: Vec<OpenAIErrorDetail> },
+    Error { error: OpenAIErrorMessage },
 }
 
 impl Display for OpenAIError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for (i, item) in self.detail.iter().enumerate() {
-            if i != 0 {
-                writeln!(f)?;
+        match self {
+            Self::Detail { detail } => {
+                for (i, item) in detail.iter().enumerate() {
+                    if i != 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}: {} ({})", item.loc, item.msg, item.r#type)?;
+                }
+            }
+            Self::Error { error } => {
+                write!(f, "{}", error.message)?;
+                match (&error.r#type, &error.code) {
+                    (Some(r#type), Some(code)) => write!(f, " ({type}, {code})")?,
+                    (Some(r#type), None) => write!(f, " ({type})")?,
+                    (None, Some(code)) => write!(f, " ({code})")?,
+                    (None, None) => (),
+                }
             }
-            write!(f, "{}: {} ({})", item.loc, item.msg, item.r#type)?;
         }
         Ok(())
     }
@@ -201,10 +500,6 @@ enum OpenAIAPIResponse {
     Error(OpenAIError),
 }
 
-fn build_openai_headers(api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
-    build_api_headers(api_token, ide)
-}
-
 fn parse_openai_text(text: &str) -> Result<Vec<Generation>> {
     match serde_json::from_str(text)? {
         OpenAIAPIResponse::Generation(completion) => {
@@ -214,51 +509,1656 @@ fn parse_openai_text(text: &str) -> Result<Vec<Generation>> {
     }
 }
 
-pub(crate) fn build_body(
-    backend: &Backend,
-    model: String,
-    prompt: String,
-    mut request_body: Map<String, Value>,
-) -> Map<String, Value> {
-    match backend {
-        Backend::HuggingFace { .. } | Backend::Tgi { .. } => {
-            request_body.insert("inputs".to_owned(), Value::String(prompt));
-            if let Some(Value::Object(params)) = request_body.get_mut("parameters") {
-                params.insert("return_full_text".to_owned(), Value::Bool(false));
-            } else {
-                let params = json!({ "parameters": { "return_full_text": false } });
-                request_body.insert("parameters".to_owned(), params);
+fn parse_openai_stream_chunk(data: &str) -> Result<StreamChunk> {
+    if data == "[DONE]" {
+        return Ok(StreamChunk::Done);
+    }
+    match serde_json::from_str(data)? {
+        OpenAIAPIResponse::Generation(completion) => Ok(StreamChunk::Text(
+            completion.choices.into_iter().map(|x| x.text).collect(),
+        )),
+        OpenAIAPIResponse::Error(err) => Err(Error::OpenAI(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatMessage {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatGenerationChoice {
+    message: OpenAIChatMessage,
+    #[serde(default)]
+    logprobs: Option<CompletionLogprobs>,
+}
+
+impl From<OpenAIChatGenerationChoice> for Generation {
+    fn from(value: OpenAIChatGenerationChoice) -> Self {
+        let content = value.message.content.unwrap_or_default();
+        let generated_text = strip_code_fences(&content);
+        Generation {
+            // the log probabilities are those of the whole answer, fences included
+            tokens: value.logprobs.and_then(CompletionLogprobs::into_tokens),
+            generated_text,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatGeneration {
+    choices: Vec<OpenAIChatGenerationChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIChatAPIResponse {
+    Generation(OpenAIChatGeneration),
+    Error(OpenAIError),
+}
+
+/// Code of the first markdown code block of a chat answer, chat models tending to wrap code in
+/// fences even when told not to, or the answer itself when it has none.
+fn strip_code_fences(answer: &str) -> String {
+    let start = if answer.starts_with("```") {
+        0
+    } else if let Some(idx) = answer.find("\n```") {
+        idx + 1
+    } else {
+        return answer.to_owned();
+    };
+    // skips the opening fence along with its language
+    let Some((_, code)) = answer[start..].split_once('\n') else {
+        return String::new();
+    };
+    let end = if code.starts_with("```") {
+        0
+    } else {
+        code.find("\n```").unwrap_or(code.len())
+    };
+    code[..end].to_owned()
+}
+
+fn parse_openai_chat_text(text: &str) -> Result<Vec<Generation>> {
+    match serde_json::from_str(text)? {
+        OpenAIChatAPIResponse::Generation(completion) => {
+            Ok(completion.choices.into_iter().map(|x| x.into()).collect())
+        }
+        OpenAIChatAPIResponse::Error(err) => Err(Error::OpenAI(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatStreamChoice {
+    delta: OpenAIChatMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIChatStreamChunk {
+    choices: Vec<OpenAIChatStreamChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum OpenAIChatStreamAPIResponse {
+    Chunk(OpenAIChatStreamChunk),
+    Error(OpenAIError),
+}
+
+fn parse_openai_chat_stream_chunk(data: &str) -> Result<StreamChunk> {
+    if data == "[DONE]" {
+        return Ok(StreamChunk::Done);
+    }
+    match serde_json::from_str(data)? {
+        OpenAIChatStreamAPIResponse::Chunk(chunk) => Ok(StreamChunk::Text(
+            chunk
+                .choices
+                .into_iter()
+                .filter_map(|x| x.delta.content)
+                .collect(),
+        )),
+        OpenAIChatStreamAPIResponse::Error(err) => Err(Error::OpenAI(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicErrorMessage {
+    r#type: String,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnthropicError {
+    error: AnthropicErrorMessage,
+}
+
+impl Display for AnthropicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.error.message, self.error.r#type)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicContentBlock {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicMessage {
+    content: Vec<AnthropicContentBlock>,
+}
+
+impl From<AnthropicMessage> for Generation {
+    fn from(value: AnthropicMessage) -> Self {
+        let answer = value
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                AnthropicContentBlock::Text { text } => Some(text),
+                AnthropicContentBlock::Other => None,
+            })
+            .collect::<String>();
+        Generation {
+            generated_text: strip_code_fences(&answer),
+            tokens: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicAPIResponse {
+    Message(AnthropicMessage),
+    Error(AnthropicError),
+}
+
+fn parse_anthropic_text(text: &str) -> Result<Vec<Generation>> {
+    match serde_json::from_str(text)? {
+        AnthropicAPIResponse::Message(message) => Ok(vec![message.into()]),
+        AnthropicAPIResponse::Error(err) => Err(Error::Anthropic(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicDelta {
+    TextDelta {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicStreamEvent {
+    ContentBlockDelta {
+        delta: AnthropicDelta,
+    },
+    MessageStop,
+    Error {
+        error: AnthropicErrorMessage,
+    },
+    #[serde(other)]
+    Other,
+}
+
+fn parse_anthropic_stream_chunk(data: &str) -> Result<StreamChunk> {
+    match serde_json::from_str(data)? {
+        AnthropicStreamEvent::ContentBlockDelta {
+            delta: AnthropicDelta::TextDelta { text },
+        } => Ok(StreamChunk::Text(text)),
+        AnthropicStreamEvent::MessageStop => Ok(StreamChunk::Done),
+        AnthropicStreamEvent::Error { error } => Err(Error::Anthropic(AnthropicError { error })),
+        _ => Ok(StreamChunk::Text(String::new())),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MistralErrorDetail {
+    loc: Vec<OpenAIErrorLoc>,
+    msg: String,
+    r#type: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MistralErrorMessage {
+    Text(String),
+    Detail { detail: Vec<MistralErrorDetail> },
+}
+
+/// Errors of Mistral's API, whose message holds the validation errors of the request body if
+/// any
+#[derive(Debug, Deserialize)]
+pub struct MistralError {
+    message: MistralErrorMessage,
+    #[serde(default)]
+    r#type: Option<String>,
+}
+
+impl Display for MistralError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.message {
+            MistralErrorMessage::Text(message) => write!(f, "{message}")?,
+            MistralErrorMessage::Detail { detail } => {
+                for (i, item) in detail.iter().enumerate() {
+                    if i != 0 {
+                        writeln!(f)?;
+                    }
+                    let loc = item
+                        .loc
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(".");
+                    write!(f, "{loc}: {} ({})", item.msg, item.r#type)?;
+                }
             }
         }
-        Backend::Ollama { .. } | Backend::OpenAi { .. } | Backend::LlamaCpp { .. } => {
-            request_body.insert("prompt".to_owned(), Value::String(prompt));
-            request_body.insert("model".to_owned(), Value::String(model));
-            request_body.insert("stream".to_owned(), Value::Bool(false));
+        if let Some(r#type) = &self.r#type {
+            write!(f, " ({type})")?;
         }
-    };
-    request_body
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CodestralGenerationChoice {
+    message: OpenAIChatMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodestralGeneration {
+    choices: Vec<CodestralGenerationChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CodestralAPIResponse {
+    Generation(CodestralGeneration),
+    Error(MistralError),
+}
+
+fn parse_codestral_text(text: &str) -> Result<Vec<Generation>> {
+    match serde_json::from_str(text)? {
+        CodestralAPIResponse::Generation(completion) => Ok(completion
+            .choices
+            .into_iter()
+            .map(|choice| Generation {
+                generated_text: choice.message.content.unwrap_or_default(),
+                tokens: None,
+            })
+            .collect()),
+        CodestralAPIResponse::Error(err) => Err(Error::Mistral(err)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CodestralStreamAPIResponse {
+    Chunk(OpenAIChatStreamChunk),
+    Error(MistralError),
 }
 
-pub(crate) fn build_headers(
-    backend: &Backend,
-    api_token: Option<&String>,
-    ide: Ide,
-) -> Result<HeaderMap> {
-    match backend {
-        Backend::HuggingFace { .. } => build_api_headers(api_token, ide),
-        Backend::LlamaCpp { .. } => build_llamacpp_headers(api_token, ide),
-        Backend::Ollama { .. } => build_ollama_headers(api_token, ide),
-        Backend::OpenAi { .. } => build_openai_headers(api_token, ide),
-        Backend::Tgi { .. } => build_tgi_headers(api_token, ide),
+fn parse_codestral_stream_chunk(data: &str) -> Result<StreamChunk> {
+    if data == "[DONE]" {
+        return Ok(StreamChunk::Done);
+    }
+    match serde_json::from_str(data)? {
+        CodestralStreamAPIResponse::Chunk(chunk) => Ok(StreamChunk::Text(
+            chunk
+                .choices
+                .into_iter()
+                .filter_map(|x| x.delta.content)
+                .collect(),
+        )),
+        CodestralStreamAPIResponse::Error(err) => Err(Error::Mistral(err)),
     }
 }
 
-pub(crate) fn parse_generations(backend: &Backend, text: &str) -> Result<Vec<Generation>> {
-    match backend {
-        Backend::HuggingFace { .. } => parse_api_text(text),
-        Backend::LlamaCpp { .. } => parse_llamacpp_text(text),
-        Backend::Ollama { .. } => parse_ollama_text(text),
-        Backend::OpenAi { .. } => parse_openai_text(text),
-        Backend::Tgi { .. } => parse_tgi_text(text),
+/// A single event decoded from a streamed backend response.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum StreamChunk {
+    Text(String),
+    Done,
+}
+
+/// Parameters of a request sent to a backend.
+pub(crate) struct CompletionRequest<'a> {
+    pub(crate) model: &'a str,
+    pub(crate) prompt: String,
+    /// Code after the cursor, for backends with a split [`PromptLayout`]
+    pub(crate) suffix: Option<String>,
+    /// Cross-file snippets, for backends with the [`PromptLayout::SplitSnippets`] layout
+    pub(crate) snippets: &'a [Snippet],
+    pub(crate) request_body: Map<String, Value>,
+    pub(crate) stop_tokens: &'a [String],
+    /// Number of generations to request, at most [`CompletionBackend::max_num_candidates`]
+    pub(crate) num_candidates: usize,
+    /// Whether several candidates are requested in all, possibly through several requests, in
+    /// which case sampling is turned on for the backends that only sample on demand
+    pub(crate) sample: bool,
+    pub(crate) stream: bool,
+    pub(crate) chat: &'a ChatParams,
+}
+
+/// API of a completion backend. Implementations are looked up in the [`BackendRegistry`] by the
+/// name of the `backend` set in the configuration.
+pub(crate) trait CompletionBackend: Send + Sync {
+    /// Name of the backend in the configuration, followed by `/{mode}` for the modes of a
+    /// backend other than its default one, e.g. `llamacpp/infill`.
+    fn name(&self) -> &'static str;
+
+    /// Completes the configured `url` with the path of the backend's route, unless it is
+    /// already there.
+    fn build_url(&self, url: String, model: &str, stream: bool) -> String;
+
+    /// Builds the body of the request. The log probabilities of the tokens are requested unless
+    /// streaming, to rank and filter the generations.
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value>;
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        build_headers(api_token, ide)
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>>;
+
+    /// Extracts the payload of a line of a streamed response. Backends use server-sent events
+    /// by default, in which case only `data` fields are relevant.
+    fn stream_line_payload<'a>(&self, line: &'a str) -> Option<&'a str> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        line.strip_prefix("data:").map(str::trim_start)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk>;
+
+    /// Error for a response status that the body is not parsed for, if any.
+    fn error_for_status(&self, status: StatusCode) -> Option<Error> {
+        (status == StatusCode::TOO_MANY_REQUESTS).then_some(Error::RateLimited)
+    }
+
+    /// Number of sequences the backend generates for a single request at most, the other
+    /// candidates are requested through as many concurrent requests as needed.
+    fn max_num_candidates(&self) -> usize {
+        1
+    }
+
+    /// Whether the generated text can be forwarded to the client as it is streamed.
+    fn supports_streaming(&self) -> bool {
+        true
+    }
+
+    /// Layout of the prompt when `fim.template` is not set, for backends serving models that
+    /// are not trained with FIM tokens.
+    fn default_fim_template(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn prompt_layout(&self) -> PromptLayout {
+        PromptLayout::Fim
+    }
+}
+
+/// Appends `{dir}/{route}` to the URL, or the part of it the URL does not end with already.
+fn complete_url_path(mut url: String, dir: &str, route: &str) -> String {
+    if url.ends_with(&format!("/{dir}/{route}")) {
+        return url;
+    }
+    if url.ends_with(&format!("/{dir}/")) {
+        url.push_str(route);
+    } else if url.ends_with(&format!("/{dir}")) {
+        url.push('/');
+        url.push_str(route);
+    } else if url.ends_with('/') {
+        url.push_str(&format!("{dir}/{route}"));
+    } else {
+        url.push_str(&format!("/{dir}/{route}"));
+    }
+    url
+}
+
+/// Body of text-generation-inference's `/generate` routes, also used by the inference API.
+fn build_tgi_body(request: CompletionRequest) -> Map<String, Value> {
+    let mut request_body = request.request_body;
+    request_body.insert("inputs".to_owned(), Value::String(request.prompt));
+    if !matches!(request_body.get("parameters"), Some(Value::Object(_))) {
+        request_body.insert("parameters".to_owned(), json!({}));
+    }
+    if let Some(Value::Object(params)) = request_body.get_mut("parameters") {
+        // default generation parameters, unless set in the request body
+        params.entry("max_new_tokens").or_insert_with(|| json!(60));
+        params.entry("temperature").or_insert_with(|| json!(0.2));
+        params.entry("top_p").or_insert_with(|| json!(0.95));
+        params.insert("return_full_text".to_owned(), Value::Bool(false));
+        insert_stop_tokens(
+            params,
+            "stop",
+            request.stop_tokens,
+            Some(MAX_STOP_SEQUENCES),
+        );
+        if !request.stream {
+            params.entry("details").or_insert(Value::Bool(true));
+        }
+        if request.num_candidates > 1 {
+            params
+                .entry("best_of")
+                .or_insert_with(|| json!(request.num_candidates));
+        }
+        // identical requests would otherwise generate identical sequences
+        if request.sample {
+            params.entry("do_sample").or_insert(Value::Bool(true));
+        }
+    }
+    request_body
+}
+
+/// Body of the backends taking the prompt and the model as top level fields.
+fn build_prompt_body(request: CompletionRequest) -> Map<String, Value> {
+    let mut request_body = request.request_body;
+    request_body.insert("prompt".to_owned(), Value::String(request.prompt));
+    request_body.insert("model".to_owned(), Value::String(request.model.to_owned()));
+    request_body.insert("stream".to_owned(), Value::Bool(request.stream));
+    request_body
+}
+
+/// Version of Anthropic's API the requests and responses follow
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Maximum number of tokens to generate, which Anthropic's API requires, unless set in the
+/// request body
+const ANTHROPIC_MAX_TOKENS: usize = 256;
+
+/// Asks chat models for the code between the prefix and the suffix, as they are not trained
+/// with FIM tokens.
+const CHAT_FIM_TEMPLATE: &str = "{context}<file path=\"{filename}\">\n{prefix}<CURSOR>{suffix}\n</file>\n\nReply with the code to insert in place of <CURSOR>, without repeating the code around it.";
+
+pub(crate) struct AnthropicBackend;
+
+impl CompletionBackend for AnthropicBackend {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn build_url(&self, url: String, _model: &str, _stream: bool) -> String {
+        complete_url_path(url, "v1", "messages")
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        let mut request_body = request.request_body;
+        request_body.insert("model".to_owned(), Value::String(request.model.to_owned()));
+        request_body
+            .entry("max_tokens")
+            .or_insert_with(|| json!(ANTHROPIC_MAX_TOKENS));
+        if !request.chat.system.is_empty() {
+            request_body.insert(
+                "system".to_owned(),
+                Value::String(request.chat.system.clone()),
+            );
+        }
+        let user = request.chat.user.replace("{prompt}", &request.prompt);
+        request_body.insert(
+            "messages".to_owned(),
+            json!([{ "role": "user", "content": user }]),
+        );
+        request_body.insert("stream".to_owned(), Value::Bool(request.stream));
+        // stop sequences made of whitespace only are rejected
+        let stop_tokens = request
+            .stop_tokens
+            .iter()
+            .filter(|token| !token.trim().is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+        insert_stop_tokens(&mut request_body, "stop_sequences", &stop_tokens, None);
+        request_body
+    }
+
+    fn build_headers(&self, api_token: Option<&String>, ide: Ide) -> Result<HeaderMap> {
+        let mut headers = build_headers(None, ide)?;
+        if let Some(api_token) = api_token {
+            headers.insert(
+                HeaderName::from_static("x-api-key"),
+                HeaderValue::from_str(api_token)?,
+            );
+        }
+        headers.insert(
+            HeaderName::from_static("anthropic-version"),
+            HeaderValue::from_static(ANTHROPIC_VERSION),
+        );
+        Ok(headers)
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_anthropic_text(text)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_anthropic_stream_chunk(data)
+    }
+
+    /// Anthropic's API answers with `529 Overloaded` when under load, to be retried later like
+    /// rate limits.
+    fn error_for_status(&self, status: StatusCode) -> Option<Error> {
+        (status == StatusCode::TOO_MANY_REQUESTS || status.as_u16() == 529)
+            .then_some(Error::RateLimited)
+    }
+
+    /// Code fences can only be stripped from the whole answer.
+    fn supports_streaming(&self) -> bool {
+        false
+    }
+
+    fn default_fim_template(&self) -> Option<&'static str> {
+        Some(CHAT_FIM_TEMPLATE)
+    }
+}
+
+pub(crate) struct CodestralBackend;
+
+impl CompletionBackend for CodestralBackend {
+    fn name(&self) -> &'static str {
+        "codestral"
+    }
+
+    fn build_url(&self, url: String, _model: &str, _stream: bool) -> String {
+        complete_url_path(url, "v1", "fim/completions")
+    }
+
+    fn build_body(&self, mut request: CompletionRequest) -> Map<String, Value> {
+        let (suffix, stop_tokens) = (request.suffix.take(), request.stop_tokens);
+        let mut request_body = build_prompt_body(request);
+        if let Some(suffix) = suffix {
+            request_body.insert("suffix".to_owned(), Value::String(suffix));
+        }
+        insert_stop_tokens(&mut request_body, "stop", stop_tokens, None);
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_codestral_text(text)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_codestral_stream_chunk(data)
+    }
+
+    fn prompt_layout(&self) -> PromptLayout {
+        PromptLayout::Split
+    }
+}
+
+pub(crate) struct HuggingFaceBackend;
+
+impl CompletionBackend for HuggingFaceBackend {
+    fn name(&self) -> &'static str {
+        "huggingface"
+    }
+
+    fn build_url(&self, url: String, model: &str, _stream: bool) -> String {
+        format!("{url}/models/{model}")
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        let stream = request.stream;
+        let mut request_body = build_tgi_body(request);
+        // the inference API streams through the `stream` field rather than a dedicated route
+        if stream {
+            request_body.insert("stream".to_owned(), Value::Bool(true));
+        }
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_api_text(text)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_api_stream_chunk(data)
+    }
+}
+
+pub(crate) struct LlamaCppBackend;
+
+impl CompletionBackend for LlamaCppBackend {
+    fn name(&self) -> &'static str {
+        "llamacpp"
+    }
+
+    fn build_url(&self, url: String, _model: &str, _stream: bool) -> String {
+        complete_url_path(url, "v1", "completions")
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        let (stop_tokens, stream) = (request.stop_tokens, request.stream);
+        let mut request_body = build_prompt_body(request);
+        insert_stop_tokens(&mut request_body, "stop", stop_tokens, None);
+        if !stream {
+            request_body.entry("n_probs").or_insert_with(|| json!(1));
+        }
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_llamacpp_text(text)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_llamacpp_stream_chunk(data)
+    }
+}
+
+pub(crate) struct LlamaCppInfillBackend;
+
+impl CompletionBackend for LlamaCppInfillBackend {
+    fn name(&self) -> &'static str {
+        "llamacpp/infill"
+    }
+
+    fn build_url(&self, mut url: String, _model: &str, _stream: bool) -> String {
+        if !url.ends_with("/infill") {
+            if !url.ends_with('/') {
+                url.push('/');
+            }
+            url.push_str("infill");
+        }
+        url
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        let mut request_body = request.request_body;
+        request_body.insert("input_prefix".to_owned(), Value::String(request.prompt));
+        request_body.insert(
+            "input_suffix".to_owned(),
+            Value::String(request.suffix.unwrap_or_default()),
+        );
+        if !request.snippets.is_empty() {
+            let input_extra = request
+                .snippets
+                .iter()
+                .map(|snippet| json!({ "filename": snippet.path, "text": snippet.content }))
+                .collect();
+            request_body.insert("input_extra".to_owned(), Value::Array(input_extra));
+        }
+        request_body.insert("stream".to_owned(), Value::Bool(request.stream));
+        insert_stop_tokens(&mut request_body, "stop", request.stop_tokens, None);
+        if !request.stream {
+            request_body.entry("n_probs").or_insert_with(|| json!(1));
+        }
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_llamacpp_infill_text(text)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_llamacpp_infill_stream_chunk(data)
+    }
+
+    /// The server adds the model's FIM tokens itself.
+    fn prompt_layout(&self) -> PromptLayout {
+        PromptLayout::SplitSnippets
+    }
+}
+
+pub(crate) struct OllamaBackend;
+
+impl CompletionBackend for OllamaBackend {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn build_url(&self, url: String, _model: &str, _stream: bool) -> String {
+        complete_url_path(url, "api", "generate")
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        let stop_tokens = request.stop_tokens;
+        let mut request_body = build_prompt_body(request);
+        if !matches!(request_body.get("options"), Some(Value::Object(_))) {
+            request_body.insert("options".to_owned(), json!({}));
+        }
+        if let Some(Value::Object(options)) = request_body.get_mut("options") {
+            insert_stop_tokens(options, "stop", stop_tokens, None);
+        }
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_ollama_text(text)
+    }
+
+    /// Ollama streams newline delimited JSON.
+    fn stream_line_payload<'a>(&self, line: &'a str) -> Option<&'a str> {
+        Some(line.trim()).filter(|line| !line.is_empty())
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_ollama_stream_chunk(data)
+    }
+}
+
+pub(crate) struct OpenAiBackend;
+
+impl CompletionBackend for OpenAiBackend {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn build_url(&self, url: String, _model: &str, _stream: bool) -> String {
+        complete_url_path(url, "v1", "completions")
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        let (stop_tokens, num_candidates, stream) =
+            (request.stop_tokens, request.num_candidates, request.stream);
+        let mut request_body = build_prompt_body(request);
+        insert_stop_tokens(
+            &mut request_body,
+            "stop",
+            stop_tokens,
+            Some(MAX_STOP_SEQUENCES),
+        );
+        if num_candidates > 1 {
+            request_body
+                .entry("n")
+                .or_insert_with(|| json!(num_candidates));
+        }
+        if !stream {
+            request_body.entry("logprobs").or_insert_with(|| json!(1));
+        }
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_openai_text(text)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_openai_stream_chunk(data)
+    }
+
+    fn max_num_candidates(&self) -> usize {
+        usize::MAX
+    }
+}
+
+pub(crate) struct OpenAiChatBackend;
+
+impl CompletionBackend for OpenAiChatBackend {
+    fn name(&self) -> &'static str {
+        "openaichat"
+    }
+
+    fn build_url(&self, url: String, _model: &str, _stream: bool) -> String {
+        complete_url_path(url, "v1", "chat/completions")
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        let mut request_body = request.request_body;
+        let mut messages = vec![];
+        if !request.chat.system.is_empty() {
+            messages.push(json!({ "role": "system", "content": request.chat.system }));
+        }
+        let user = request.chat.user.replace("{prompt}", &request.prompt);
+        messages.push(json!({ "role": "user", "content": user }));
+        request_body.insert("messages".to_owned(), Value::Array(messages));
+        request_body.insert("model".to_owned(), Value::String(request.model.to_owned()));
+        request_body.insert("stream".to_owned(), Value::Bool(request.stream));
+        insert_stop_tokens(
+            &mut request_body,
+            "stop",
+            request.stop_tokens,
+            Some(MAX_STOP_SEQUENCES),
+        );
+        if request.num_candidates > 1 {
+            request_body
+                .entry("n")
+                .or_insert_with(|| json!(request.num_candidates));
+        }
+        if !request.stream {
+            request_body.entry("logprobs").or_insert(Value::Bool(true));
+        }
+        request_body
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_openai_chat_text(text)
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_openai_chat_stream_chunk(data)
+    }
+
+    fn max_num_candidates(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Code fences can only be stripped from the whole answer.
+    fn supports_streaming(&self) -> bool {
+        false
+    }
+
+    fn default_fim_template(&self) -> Option<&'static str> {
+        Some(CHAT_FIM_TEMPLATE)
+    }
+}
+
+pub(crate) struct TgiBackend;
+
+impl CompletionBackend for TgiBackend {
+    fn name(&self) -> &'static str {
+        "tgi"
+    }
+
+    fn build_url(&self, url: String, _model: &str, stream: bool) -> String {
+        let route = if stream {
+            "generate_stream"
+        } else {
+            "generate"
+        };
+        // the configured url may already point to either route
+        let mut url = match ["/generate_stream", "/generate"]
+            .into_iter()
+            .find_map(|known_route| url.strip_suffix(known_route))
+        {
+            Some(base) => base.to_owned(),
+            None => url,
+        };
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        url.push_str(route);
+        url
+    }
+
+    fn build_body(&self, request: CompletionRequest) -> Map<String, Value> {
+        build_tgi_body(request)
+    }
+
+    fn parse_generations(&self, text: &str) -> Result<Vec<Generation>> {
+        parse_tgi_text(text)
+    }
+
+    fn max_num_candidates(&self) -> usize {
+        MAX_BEST_OF
+    }
+
+    fn parse_stream_chunk(&self, data: &str) -> Result<StreamChunk> {
+        parse_tgi_stream_chunk(data)
+    }
+}
+
+/// Implementations of the backends by name, the built-in ones being registered by default.
+pub(crate) struct BackendRegistry {
+    backends: HashMap<&'static str, Box<dyn CompletionBackend>>,
+}
+
+impl Default for BackendRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            backends: HashMap::new(),
+        };
+        registry.register(AnthropicBackend);
+        registry.register(CodestralBackend);
+        registry.register(HuggingFaceBackend);
+        registry.register(LlamaCppBackend);
+        registry.register(LlamaCppInfillBackend);
+        registry.register(OllamaBackend);
+        registry.register(OpenAiBackend);
+        registry.register(OpenAiChatBackend);
+        registry.register(TgiBackend);
+        registry
+    }
+}
+
+impl BackendRegistry {
+    /// Registers a backend, replacing the one with the same name if any.
+    pub(crate) fn register(&mut self, backend: impl CompletionBackend + 'static) {
+        self.backends.insert(backend.name(), Box::new(backend));
+    }
+
+    pub(crate) fn get(&self, backend: &Backend) -> Result<&dyn CompletionBackend> {
+        let name = match backend.mode() {
+            Some(mode) => format!("{}/{mode}", backend.name()),
+            None => backend.name().to_owned(),
+        };
+        self.backends
+            .get(name.as_str())
+            .map(Box::as_ref)
+            .ok_or(Error::UnknownBackend(name))
+    }
+}
+
+/// Maximum number of stop sequences accepted by text-generation-inference and OpenAI
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Default `--max-best-of` of text-generation-inference, which rejects requests with a higher
+/// `best_of`
+const MAX_BEST_OF: usize = 2;
+
+/// Sets the stop sequences, unless the request body already does, keeping the first `max` ones
+/// for backends that reject longer lists.
+fn insert_stop_tokens(
+    object: &mut Map<String, Value>,
+    key: &str,
+    stop_tokens: &[String],
+    max: Option<usize>,
+) {
+    let stop_tokens = match max {
+        Some(max) if stop_tokens.len() > max => {
+            warn!(
+                "only sending the first {max} stop sequences, dropping {:?}",
+                &stop_tokens[max..]
+            );
+            &stop_tokens[..max]
+        }
+        _ => stop_tokens,
+    };
+    if !stop_tokens.is_empty() && !object.contains_key(key) {
+        object.insert(key.to_owned(), json!(stop_tokens));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use custom_types::llm_ls::{CompletionType, LlamaCppMode, LlmLsConfig};
+    use custom_types::preset::Preset;
+    use std::sync::OnceLock;
+
+    use crate::language_id::LanguageId;
+
+    fn request(
+        request_body: Map<String, Value>,
+        stop_tokens: &[String],
+        num_candidates: usize,
+        stream: bool,
+    ) -> CompletionRequest<'_> {
+        static CHAT: OnceLock<ChatParams> = OnceLock::new();
+        CompletionRequest {
+            model: "model",
+            prompt: String::new(),
+            suffix: None,
+            snippets: &[],
+            request_body,
+            stop_tokens,
+            num_candidates,
+            sample: num_candidates > 1,
+            stream,
+            chat: CHAT.get_or_init(ChatParams::default),
+        }
+    }
+
+    #[test]
+    fn test_build_url() {
+        let url = |backend: &dyn CompletionBackend, url: &str, stream: bool| {
+            backend.build_url(url.to_owned(), "bigcode/starcoder", stream)
+        };
+        assert_eq!(
+            url(&HuggingFaceBackend, "https://api.example.com", false),
+            "https://api.example.com/models/bigcode/starcoder"
+        );
+        for backend in [&LlamaCppBackend as &dyn CompletionBackend, &OpenAiBackend] {
+            for base in [
+                "http://localhost:8080",
+                "http://localhost:8080/",
+                "http://localhost:8080/v1",
+                "http://localhost:8080/v1/",
+                "http://localhost:8080/v1/completions",
+            ] {
+                assert_eq!(
+                    url(backend, base, false),
+                    "http://localhost:8080/v1/completions"
+                );
+            }
+        }
+        for base in [
+            "http://localhost:8080",
+            "http://localhost:8080/",
+            "http://localhost:8080/generate",
+            "http://localhost:8080/generate_stream",
+        ] {
+            assert_eq!(
+                url(&TgiBackend, base, false),
+                "http://localhost:8080/generate"
+            );
+            assert_eq!(
+                url(&TgiBackend, base, true),
+                "http://localhost:8080/generate_stream"
+            );
+        }
+        assert_eq!(
+            url(&CodestralBackend, "https://codestral.mistral.ai/v1/", false),
+            "https://codestral.mistral.ai/v1/fim/completions"
+        );
+        assert_eq!(
+            url(&AnthropicBackend, "https://api.anthropic.com", false),
+            "https://api.anthropic.com/v1/messages"
+        );
+        assert_eq!(
+            url(&OpenAiChatBackend, "http://localhost:8000/v1", false),
+            "http://localhost:8000/v1/chat/completions"
+        );
+        assert_eq!(
+            url(
+                &OpenAiChatBackend,
+                "http://localhost:8000/v1/chat/completions",
+                false
+            ),
+            "http://localhost:8000/v1/chat/completions"
+        );
+        for base in [
+            "http://localhost:11434",
+            "http://localhost:11434/api",
+            "http://localhost:11434/api/generate",
+        ] {
+            assert_eq!(
+                url(&OllamaBackend, base, true),
+                "http://localhost:11434/api/generate"
+            );
+        }
+        assert_eq!(
+            url(&TgiBackend, "http://localhost:8080/", false),
+            "http://localhost:8080/generate"
+        );
+        assert_eq!(
+            url(&TgiBackend, "http://localhost:8080", true),
+            "http://localhost:8080/generate_stream"
+        );
+        assert_eq!(
+            url(&TgiBackend, "http://localhost:8080/generate", true),
+            "http://localhost:8080/generate_stream"
+        );
+    }
+
+    #[test]
+    fn test_backend_registry() {
+        let registry = BackendRegistry::default();
+        for (backend, name) in [
+            (Backend::Anthropic { url: String::new() }, "anthropic"),
+            (Backend::Codestral { url: String::new() }, "codestral"),
+            (Backend::default(), "huggingface"),
+            (
+                Backend::LlamaCpp {
+                    url: String::new(),
+                    mode: LlamaCppMode::default(),
+                },
+                "llamacpp",
+            ),
+            (Backend::Ollama { url: String::new() }, "ollama"),
+            (Backend::OpenAi { url: String::new() }, "openai"),
+            (Backend::OpenAiChat { url: String::new() }, "openaichat"),
+            (Backend::Tgi { url: String::new() }, "tgi"),
+        ] {
+            assert_eq!(registry.get(&backend).unwrap().name(), name);
+            // names match the ones used in the configuration
+            assert_eq!(
+                serde_json::to_value(&backend).unwrap()["backend"],
+                json!(name)
+            );
+        }
+
+        let infill = Backend::LlamaCpp {
+            url: String::new(),
+            mode: LlamaCppMode::Infill,
+        };
+        assert_eq!(registry.get(&infill).unwrap().name(), "llamacpp/infill");
+
+        let registry = BackendRegistry {
+            backends: HashMap::new(),
+        };
+        assert!(matches!(
+            registry.get(&Backend::default()),
+            Err(Error::UnknownBackend(name)) if name == "huggingface"
+        ));
+    }
+
+    #[test]
+    fn test_build_headers() {
+        let token = "hf_token".to_owned();
+        let headers = TgiBackend
+            .build_headers(Some(&token), Ide::default())
+            .unwrap();
+        assert_eq!(headers[AUTHORIZATION], "Bearer hf_token");
+        assert!(headers[USER_AGENT].to_str().unwrap().starts_with(NAME));
+        let headers = OllamaBackend.build_headers(None, Ide::default()).unwrap();
+        assert!(headers.get(AUTHORIZATION).is_none());
+    }
+
+    #[test]
+    fn test_error_for_status() {
+        assert!(matches!(
+            OpenAiBackend.error_for_status(StatusCode::TOO_MANY_REQUESTS),
+            Some(Error::RateLimited)
+        ));
+        assert!(TgiBackend.error_for_status(StatusCode::OK).is_none());
+    }
+
+    #[test]
+    fn test_build_body_stop_tokens() {
+        let stop_tokens = vec!["<EOT>".to_owned()];
+        let body = TgiBackend.build_body(request(Map::new(), &stop_tokens, 1, false));
+        assert_eq!(body["parameters"]["stop"], json!(["<EOT>"]));
+        assert_eq!(body["parameters"]["return_full_text"], json!(false));
+
+        let body = OllamaBackend.build_body(request(Map::new(), &stop_tokens, 1, false));
+        assert_eq!(body["options"]["stop"], json!(["<EOT>"]));
+        assert_eq!(body["model"], json!("model"));
+
+        // the preset tokens come before the derived ones, which are dropped first
+        let stop_tokens = crate::stop_sequences(
+            &CompletionType::MultiLine,
+            LanguageId::Python,
+            Preset::QwenCoder.overrides().stop_tokens.unwrap(),
+        );
+        let body = TgiBackend.build_body(request(Map::new(), &stop_tokens, 1, false));
+        assert_eq!(
+            body["parameters"]["stop"],
+            json!(["<|endoftext|>", "<|file_sep|>", "<|fim_pad|>", "\ndef "])
+        );
+
+        let stop_tokens = (0..6).map(|i| i.to_string()).collect::<Vec<_>>();
+        let body = TgiBackend.build_body(request(Map::new(), &stop_tokens, 1, false));
+        assert_eq!(body["parameters"]["stop"], json!(["0", "1", "2", "3"]));
+        let body = LlamaCppBackend.build_body(request(Map::new(), &stop_tokens, 1, false));
+        assert_eq!(body["stop"].as_array().map(Vec::len), Some(6));
+
+        let request_body = json!({ "stop": ["\n"] }).as_object().cloned().unwrap();
+        let body = OpenAiBackend.build_body(request(request_body, &stop_tokens, 1, false));
+        assert_eq!(body["stop"], json!(["\n"]));
+    }
+
+    #[test]
+    fn test_build_body_parameters() {
+        let request_body = LlmLsConfig::default().request_body;
+        let body = TgiBackend.build_body(request(request_body.clone(), &[], 1, false));
+        assert_eq!(body["parameters"]["max_new_tokens"], json!(60));
+        assert_eq!(body["parameters"]["temperature"], json!(0.2));
+        for backend in [
+            &AnthropicBackend as &dyn CompletionBackend,
+            &CodestralBackend,
+            &OpenAiChatBackend,
+        ] {
+            let body = backend.build_body(request(request_body.clone(), &[], 1, false));
+            assert!(body.get("parameters").is_none(), "{}", backend.name());
+        }
+
+        let request_body = json!({ "parameters": { "max_new_tokens": 128 } })
+            .as_object()
+            .cloned()
+            .unwrap();
+        let body = TgiBackend.build_body(request(request_body, &[], 1, false));
+        assert_eq!(body["parameters"]["max_new_tokens"], json!(128));
+        assert_eq!(body["parameters"]["top_p"], json!(0.95));
+    }
+
+    #[test]
+    fn test_build_body_logprobs() {
+        let body = |backend: &dyn CompletionBackend, num_candidates: usize, stream: bool| {
+            backend.build_body(request(Map::new(), &[], num_candidates, stream))
+        };
+        let openai_body = body(&OpenAiBackend, 3, false);
+        assert_eq!(openai_body["n"], json!(3));
+        assert_eq!(openai_body["logprobs"], json!(1));
+        let openai_body = body(&OpenAiBackend, 1, true);
+        assert!(openai_body.get("n").is_none());
+        assert!(openai_body.get("logprobs").is_none());
+        assert_eq!(openai_body["stream"], json!(true));
+
+        assert_eq!(
+            body(&TgiBackend, 1, false)["parameters"]["details"],
+            json!(true)
+        );
+        let tgi_body = body(&TgiBackend, 1, true);
+        assert!(tgi_body["parameters"].get("details").is_none());
+        assert!(tgi_body.get("stream").is_none());
+        assert_eq!(body(&HuggingFaceBackend, 1, true)["stream"], json!(true));
+
+        assert_eq!(body(&LlamaCppBackend, 1, false)["n_probs"], json!(1));
+
+        let tgi_body = body(&TgiBackend, 2, false);
+        assert_eq!(tgi_body["parameters"]["best_of"], json!(2));
+        assert_eq!(tgi_body["parameters"]["do_sample"], json!(true));
+        assert!(body(&TgiBackend, 1, false)["parameters"]
+            .get("best_of")
+            .is_none());
+        // one of several requests for the candidates
+        let hf_body = HuggingFaceBackend.build_body(CompletionRequest {
+            sample: true,
+            ..request(Map::new(), &[], 1, false)
+        });
+        assert!(hf_body["parameters"].get("best_of").is_none());
+        assert_eq!(hf_body["parameters"]["do_sample"], json!(true));
+    }
+
+    fn logprobs(generation: &Generation) -> Option<Vec<f32>> {
+        generation
+            .tokens
+            .as_ref()
+            .map(|tokens| tokens.iter().map(|token| token.logprob).collect())
+    }
+
+    #[test]
+    fn test_parse_generations_logprobs() {
+        let generations = TgiBackend
+            .parse_generations(
+                r#"{"generated_text":"a","details":{"tokens":[{"text":"a","logprob":-0.5}],"best_of_sequences":[{"generated_text":"b","tokens":[{"text":"b","logprob":-0.1}]}]}}"#,
+            )
+            .unwrap();
+        assert_eq!(generations.len(), 2);
+        assert_eq!(generations[0].generated_text, "a");
+        assert_eq!(generations[1].generated_text, "b");
+        assert_eq!(logprobs(&generations[1]), Some(vec![-0.1]));
+
+        let generations = TgiBackend
+            .parse_generations(
+                r#"{"generated_text":"ab","details":{"tokens":[{"text":"a","logprob":-0.5},{"text":"b","logprob":-1.5},{"text":"<|endoftext|>","logprob":-0.1,"special":true}]}}"#,
+            )
+            .unwrap();
+        assert_eq!(generations[0].generated_text, "ab");
+        assert_eq!(logprobs(&generations[0]), Some(vec![-0.5, -1.5]));
+
+        let generations = TgiBackend
+            .parse_generations(
+                r#"{"generated_text":"ab","details":{"tokens":[{"text":"a","logprob":-0.5},{"text":"b","logprob":null}]}}"#,
+            )
+            .unwrap();
+        assert_eq!(logprobs(&generations[0]), None);
+
+        let generations = OpenAiBackend
+            .parse_generations(
+                r#"{"choices":[{"text":"ab","logprobs":{"tokens":["a","b"],"token_logprobs":[-0.2,-0.4]}},{"text":"b"}]}"#,
+            )
+            .unwrap();
+        assert_eq!(logprobs(&generations[0]), Some(vec![-0.2, -0.4]));
+        assert_eq!(logprobs(&generations[1]), None);
+
+        let generations = LlamaCppBackend
+            .parse_generations(
+                r#"{"choices":[{"text":"a","logprobs":{"content":[{"token":"a","logprob":-0.3}]}}]}"#,
+            )
+            .unwrap();
+        assert_eq!(logprobs(&generations[0]), Some(vec![-0.3]));
+    }
+
+    #[test]
+    fn test_parse_generations_errors() {
+        let error = r#"{"error":"model is overloaded"}"#;
+        assert!(matches!(
+            HuggingFaceBackend.parse_generations(error),
+            Err(Error::InferenceApi(_))
+        ));
+        assert!(matches!(
+            TgiBackend.parse_generations(error),
+            Err(Error::Tgi(_))
+        ));
+        assert!(matches!(
+            LlamaCppBackend.parse_generations(error),
+            Err(Error::LlamaCpp(_))
+        ));
+        assert!(matches!(
+            OllamaBackend.parse_generations(error),
+            Err(Error::Ollama(_))
+        ));
+        assert!(matches!(
+            OpenAiBackend.parse_generations(
+                r#"{"detail":[{"loc":"prompt","msg":"field required","type":"value_error"}]}"#
+            ),
+            Err(Error::OpenAI(_))
+        ));
+        // a list of generations is only returned by the inference API
+        assert!(matches!(
+            TgiBackend.parse_generations(r#"[{"generated_text":"a"}]"#),
+            Err(Error::InvalidBackend)
+        ));
+        assert_eq!(
+            HuggingFaceBackend
+                .parse_generations(r#"[{"generated_text":"a"}]"#)
+                .unwrap()[0]
+                .generated_text,
+            "a"
+        );
+        assert_eq!(
+            OllamaBackend
+                .parse_generations(r#"{"response":"a"}"#)
+                .unwrap()[0]
+                .generated_text,
+            "a"
+        );
+    }
+
+    #[test]
+    fn test_openai_chat() {
+        assert_eq!(
+            OpenAiChatBackend.default_fim_template(),
+            AnthropicBackend.default_fim_template()
+        );
+        let chat = ChatParams {
+            system: "Complete the code.".to_owned(),
+            user: "```\n{prompt}\n```".to_owned(),
+        };
+        let body = OpenAiChatBackend.build_body(CompletionRequest {
+            prompt: "def add(a, b):".to_owned(),
+            chat: &chat,
+            ..request(Map::new(), &[], 2, false)
+        });
+        assert_eq!(
+            body["messages"],
+            json!([
+                { "role": "system", "content": "Complete the code." },
+                { "role": "user", "content": "```\ndef add(a, b):\n```" },
+            ])
+        );
+        assert_eq!(body["n"], json!(2));
+        assert_eq!(body["logprobs"], json!(true));
+        assert!(body.get("prompt").is_none());
+
+        let generations = OpenAiChatBackend
+            .parse_generations(
+                r#"{"choices":[{"message":{"role":"assistant","content":"```python\n    return a + b\n```"}},{"message":{"role":"assistant","content":"return a - b"}}]}"#,
+            )
+            .unwrap();
+        assert_eq!(generations[0].generated_text, "    return a + b");
+        assert_eq!(generations[1].generated_text, "return a - b");
+
+        assert!(matches!(
+            OpenAiChatBackend.parse_generations(
+                r#"{"error":{"message":"Rate limit reached","type":"requests","code":"rate_limit_exceeded"}}"#
+            ),
+            Err(Error::OpenAI(err)) if err.to_string() == "Rate limit reached (requests, rate_limit_exceeded)"
+        ));
+        assert!(matches!(
+            OpenAiBackend.parse_generations(
+                r#"{"error":{"message":"Invalid model","type":"invalid_request_error","code":null}}"#
+            ),
+            Err(Error::OpenAI(err)) if err.to_string() == "Invalid model (invalid_request_error)"
+        ));
+
+        assert_eq!(
+            OpenAiChatBackend
+                .parse_stream_chunk(r#"{"choices":[{"index":0,"delta":{"content":"foo"}}]}"#)
+                .unwrap(),
+            StreamChunk::Text("foo".to_owned())
+        );
+        assert!(!OpenAiChatBackend.supports_streaming());
+    }
+
+    #[test]
+    fn test_anthropic() {
+        let token = "sk-ant".to_owned();
+        let headers = AnthropicBackend
+            .build_headers(Some(&token), Ide::default())
+            .unwrap();
+        assert_eq!(headers["x-api-key"], "sk-ant");
+        assert_eq!(headers["anthropic-version"], ANTHROPIC_VERSION);
+        assert!(headers.get(AUTHORIZATION).is_none());
+
+        let stop_tokens = vec!["\n".to_owned(), "\ndef ".to_owned()];
+        let body = AnthropicBackend.build_body(CompletionRequest {
+            prompt: "def add(a, b):".to_owned(),
+            ..request(Map::new(), &stop_tokens, 1, false)
+        });
+        assert_eq!(body["max_tokens"], json!(ANTHROPIC_MAX_TOKENS));
+        assert_eq!(body["system"], json!(ChatParams::default().system));
+        assert_eq!(
+            body["messages"],
+            json!([{ "role": "user", "content": "def add(a, b):" }])
+        );
+        assert_eq!(body["stop_sequences"], json!(["\ndef "]));
+
+        let generations = AnthropicBackend
+            .parse_generations(
+                r#"{"id":"msg_1","type":"message","role":"assistant","content":[{"type":"text","text":"```python\n    return a + b\n```"}],"stop_reason":"end_turn"}"#,
+            )
+            .unwrap();
+        assert_eq!(generations[0].generated_text, "    return a + b");
+        assert!(matches!(
+            AnthropicBackend.parse_generations(
+                r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#
+            ),
+            Err(Error::Anthropic(err)) if err.to_string() == "Overloaded (overloaded_error)"
+        ));
+        assert!(matches!(
+            AnthropicBackend.error_for_status(StatusCode::from_u16(529).unwrap()),
+            Some(Error::RateLimited)
+        ));
+
+        assert_eq!(
+            AnthropicBackend
+                .parse_stream_chunk(
+                    r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"foo"}}"#
+                )
+                .unwrap(),
+            StreamChunk::Text("foo".to_owned())
+        );
+        assert_eq!(
+            AnthropicBackend
+                .parse_stream_chunk(r#"{"type":"ping"}"#)
+                .unwrap(),
+            StreamChunk::Text(String::new())
+        );
+        assert_eq!(
+            AnthropicBackend
+                .parse_stream_chunk(r#"{"type":"message_stop"}"#)
+                .unwrap(),
+            StreamChunk::Done
+        );
+    }
+
+    #[test]
+    fn test_codestral() {
+        let stop_tokens = vec!["\n\n".to_owned()];
+        let body = CodestralBackend.build_body(CompletionRequest {
+            prompt: "def add(a, b):\n    ".to_owned(),
+            suffix: Some("\nprint(add(1, 2))\n".to_owned()),
+            ..request(Map::new(), &stop_tokens, 1, false)
+        });
+        assert_eq!(body["prompt"], json!("def add(a, b):\n    "));
+        assert_eq!(body["suffix"], json!("\nprint(add(1, 2))\n"));
+        assert_eq!(body["model"], json!("model"));
+        assert_eq!(body["stop"], json!(["\n\n"]));
+        assert_eq!(body["stream"], json!(false));
+        assert_eq!(CodestralBackend.prompt_layout(), PromptLayout::Split);
+
+        let generations = CodestralBackend
+            .parse_generations(
+                r#"{"id":"1","object":"chat.completion","model":"codestral-latest","choices":[{"index":0,"message":{"role":"assistant","content":"return a + b"},"finish_reason":"stop"}]}"#,
+            )
+            .unwrap();
+        assert_eq!(generations[0].generated_text, "return a + b");
+
+        assert!(matches!(
+            CodestralBackend.parse_generations(
+                r#"{"object":"error","message":"Invalid model: foo","type":"invalid_model","param":null,"code":"1500"}"#
+            ),
+            Err(Error::Mistral(err)) if err.to_string() == "Invalid model: foo (invalid_model)"
+        ));
+        assert!(matches!(
+            CodestralBackend.parse_generations(
+                r#"{"object":"error","message":{"detail":[{"type":"missing","loc":["body","prompt"],"msg":"Field required","input":{}}]},"type":"invalid_request_message_error","param":null,"code":null}"#
+            ),
+            Err(Error::Mistral(err)) if err.to_string() == "body.prompt: Field required (missing) (invalid_request_message_error)"
+        ));
+        assert!(matches!(
+            CodestralBackend.parse_generations(r#"{"message":"Unauthorized","request_id":"1"}"#),
+            Err(Error::Mistral(err)) if err.to_string() == "Unauthorized"
+        ));
+
+        assert_eq!(
+            CodestralBackend
+                .parse_stream_chunk(
+                    r#"{"id":"1","choices":[{"index":0,"delta":{"content":"foo"}}]}"#
+                )
+                .unwrap(),
+            StreamChunk::Text("foo".to_owned())
+        );
+        assert_eq!(
+            CodestralBackend.parse_stream_chunk("[DONE]").unwrap(),
+            StreamChunk::Done
+        );
+    }
+
+    #[test]
+    fn test_llamacpp_infill() {
+        assert_eq!(
+            LlamaCppInfillBackend.build_url("http://localhost:8080".to_owned(), "", false),
+            "http://localhost:8080/infill"
+        );
+        assert_eq!(
+            LlamaCppInfillBackend.build_url("http://localhost:8080/infill".to_owned(), "", true),
+            "http://localhost:8080/infill"
+        );
+
+        let snippets = [Snippet {
+            path: "utils.py".to_owned(),
+            content: "def add(a, b): ...\n".to_owned(),
+            score: 1.,
+        }];
+        let body = LlamaCppInfillBackend.build_body(CompletionRequest {
+            prompt: "print(".to_owned(),
+            suffix: Some(")\n".to_owned()),
+            snippets: &snippets,
+            ..request(Map::new(), &[], 1, false)
+        });
+        assert_eq!(body["input_prefix"], json!("print("));
+        assert_eq!(body["input_suffix"], json!(")\n"));
+        assert_eq!(
+            body["input_extra"],
+            json!([{ "filename": "utils.py", "text": "def add(a, b): ...\n" }])
+        );
+        assert_eq!(body["n_probs"], json!(1));
+        assert!(body.get("prompt").is_none());
+        assert_eq!(
+            LlamaCppInfillBackend.prompt_layout(),
+            PromptLayout::SplitSnippets
+        );
+
+        let generations = LlamaCppInfillBackend
+            .parse_generations(
+                r#"{"content":"add(1, 2)","stop":true,"completion_probabilities":[{"id":1,"token":"add","logprob":-0.1,"top_logprobs":[]},{"id":2,"token":"(1, 2)","logprob":-0.3,"top_logprobs":[]}]}"#,
+            )
+            .unwrap();
+        assert_eq!(generations[0].generated_text, "add(1, 2)");
+        assert_eq!(logprobs(&generations[0]), Some(vec![-0.1, -0.3]));
+        // older versions of the server return probabilities rather than log probabilities
+        let generations = LlamaCppInfillBackend
+            .parse_generations(
+                r#"{"content":"add","stop":true,"completion_probabilities":[{"content":"add","probs":[{"tok_str":"add","prob":0.9}]}]}"#,
+            )
+            .unwrap();
+        assert_eq!(logprobs(&generations[0]), None);
+
+        assert!(matches!(
+            LlamaCppInfillBackend.parse_generations(
+                r#"{"error":{"code":400,"message":"infill is not supported by this model","type":"invalid_request_error"}}"#
+            ),
+            Err(Error::LlamaCpp(err)) if err.to_string() == "infill is not supported by this model (invalid_request_error, 400)"
+        ));
+
+        assert_eq!(
+            LlamaCppInfillBackend
+                .parse_stream_chunk(r#"{"content":"add","stop":false}"#)
+                .unwrap(),
+            StreamChunk::Text("add".to_owned())
+        );
+        assert_eq!(
+            LlamaCppInfillBackend
+                .parse_stream_chunk(r#"{"content":"","stop":true}"#)
+                .unwrap(),
+            StreamChunk::Done
+        );
+    }
+
+    #[test]
+    fn test_strip_code_fences() {
+        assert_eq!(strip_code_fences("return a"), "return a");
+        assert_eq!(strip_code_fences("```\nreturn a\n```"), "return a");
+        assert_eq!(
+            strip_code_fences("Here you go:\n```rust\nlet a = 1;\nlet b = 2;\n```\nDone."),
+            "let a = 1;\nlet b = 2;"
+        );
+        // unterminated block
+        assert_eq!(strip_code_fences("```py\nreturn a"), "return a");
+        assert_eq!(strip_code_fences("```py"), "");
+    }
+
+    #[test]
+    fn test_stream_line_payload() {
+        assert_eq!(TgiBackend.stream_line_payload("data: {}\n"), Some("{}"));
+        assert_eq!(
+            TgiBackend.stream_line_payload("data:[DONE]"),
+            Some("[DONE]")
+        );
+        assert_eq!(TgiBackend.stream_line_payload(": keep-alive"), None);
+        assert_eq!(TgiBackend.stream_line_payload("\r\n"), None);
+        assert_eq!(OllamaBackend.stream_line_payload("{}\n"), Some("{}"));
+        assert_eq!(OllamaBackend.stream_line_payload("\n"), None);
+    }
+
+    #[test]
+    fn test_parse_stream_chunk() {
+        assert_eq!(
+            TgiBackend
+                .parse_stream_chunk(
+                    r#"{"token":{"id":1,"text":"foo","logprob":-0.1,"special":false},"generated_text":null}"#
+                )
+                .unwrap(),
+            StreamChunk::Text("foo".to_owned())
+        );
+        assert_eq!(
+            TgiBackend
+                .parse_stream_chunk(
+                    r#"{"token":{"id":0,"text":"</s>","logprob":-0.1,"special":true},"generated_text":"foo"}"#
+                )
+                .unwrap(),
+            StreamChunk::Text(String::new())
+        );
+        assert!(matches!(
+            TgiBackend.parse_stream_chunk(r#"{"error":"overloaded","error_type":"overloaded"}"#),
+            Err(Error::Tgi(_))
+        ));
+        assert!(matches!(
+            HuggingFaceBackend
+                .parse_stream_chunk(r#"{"error":"overloaded","error_type":"overloaded"}"#),
+            Err(Error::InferenceApi(_))
+        ));
+
+        assert_eq!(
+            OllamaBackend
+                .parse_stream_chunk(r#"{"response":"bar","done":false}"#)
+                .unwrap(),
+            StreamChunk::Text("bar".to_owned())
+        );
+        assert_eq!(
+            OllamaBackend
+                .parse_stream_chunk(r#"{"response":"","done":true}"#)
+                .unwrap(),
+            StreamChunk::Done
+        );
+
+        assert_eq!(
+            OpenAiBackend
+                .parse_stream_chunk(r#"{"choices":[{"text":"baz","index":0}]}"#)
+                .unwrap(),
+            StreamChunk::Text("baz".to_owned())
+        );
+        assert_eq!(
+            OpenAiBackend.parse_stream_chunk("[DONE]").unwrap(),
+            StreamChunk::Done
+        );
+        assert_eq!(
+            LlamaCppBackend.parse_stream_chunk("[DONE]").unwrap(),
+            StreamChunk::Done
+        );
     }
 }
@@ -0,0 +1,221 @@
+use custom_types::llm_ls::LlmLsConfig;
+use lru::LruCache;
+use ropey::Rope;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use crate::confidence::strip_prefix;
+use crate::prompt::Prompt;
+use crate::Generation;
+
+/// Hash of the parameters of a request other than its prompt: two requests with the same key get
+/// the same generations for the same prompt.
+pub(crate) fn config_key(config: &LlmLsConfig) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    config.model.hash(&mut hasher);
+    serde_json::to_string(&config.backend)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    serde_json::to_string(&config.request_body)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    config.stop_tokens.hash(&mut hasher);
+    config.num_candidates.hash(&mut hasher);
+    serde_json::to_string(&config.chat)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+pub(crate) fn prompt_key(config_key: u64, prompt: &Prompt) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    config_key.hash(&mut hasher);
+    prompt.text.hash(&mut hasher);
+    prompt.suffix.hash(&mut hasher);
+    for snippet in &prompt.snippets {
+        snippet.path.hash(&mut hasher);
+        snippet.content.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+struct CacheEntry {
+    config_key: u64,
+    uri: String,
+    /// Content of the document when the generations were requested
+    text: Rope,
+    /// Char index of the cursor in `text`
+    cursor: usize,
+    generations: Vec<Generation>,
+    created_at: Instant,
+}
+
+/// Generations of the latest requests, keyed by [`prompt_key`].
+pub(crate) struct CompletionCache {
+    entries: LruCache<u64, CacheEntry>,
+}
+
+impl Default for CompletionCache {
+    fn default() -> Self {
+        Self {
+            entries: LruCache::new(NonZeroUsize::MIN),
+        }
+    }
+}
+
+impl CompletionCache {
+    /// Sets the maximum number of entries, evicting the least recently used ones if needed.
+    pub(crate) fn resize(&mut self, capacity: usize) {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        if self.entries.cap() != capacity {
+            self.entries.resize(capacity);
+        }
+    }
+
+    /// Generations of a request with the same parameters and prompt.
+    pub(crate) fn get(&mut self, key: u64, ttl: Duration) -> Option<Vec<Generation>> {
+        if self
+            .entries
+            .peek(&key)
+            .is_some_and(|entry| entry.created_at.elapsed() >= ttl)
+        {
+            self.entries.pop(&key);
+        }
+        self.entries
+            .get(&key)
+            .map(|entry| entry.generations.clone())
+    }
+
+    /// Remainder of the generations of a request made earlier in the same document, when the
+    /// only change since then is text typed at the cursor that the generations start with.
+    pub(crate) fn get_typed_ahead(
+        &mut self,
+        config_key: u64,
+        uri: &str,
+        text: &Rope,
+        cursor: usize,
+        ttl: Duration,
+    ) -> Option<Vec<Generation>> {
+        let (&key, generations) = self.entries.iter().find_map(|(key, entry)| {
+            if entry.config_key != config_key
+                || entry.uri != uri
+                || entry.cursor >= cursor
+                || entry.created_at.elapsed() >= ttl
+            {
+                return None;
+            }
+            let typed_len = cursor - entry.cursor;
+            if text.len_chars() != entry.text.len_chars() + typed_len {
+                return None;
+            }
+            let typed = text.slice(entry.cursor..cursor).to_string();
+            let generations = entry
+                .generations
+                .iter()
+                .filter_map(|generation| strip_prefix(generation, &typed))
+                .filter(|generation| !generation.generated_text.is_empty())
+                .collect::<Vec<_>>();
+            let unchanged = text.slice(..entry.cursor) == entry.text.slice(..entry.cursor)
+                && text.slice(cursor..) == entry.text.slice(entry.cursor..);
+            (!generations.is_empty() && unchanged).then_some((key, generations))
+        })?;
+        // mark the entry as recently used
+        self.entries.get(&key);
+        Some(generations)
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        key: u64,
+        config_key: u64,
+        uri: String,
+        text: Rope,
+        cursor: usize,
+        generations: Vec<Generation>,
+    ) {
+        self.entries.put(
+            key,
+            CacheEntry {
+                config_key,
+                uri,
+                text,
+                cursor,
+                generations,
+                created_at: Instant::now(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn prompt(text: &str) -> Prompt {
+        Prompt {
+            text: text.to_owned(),
+            suffix: None,
+            snippets: vec![],
+            token_counts: Default::default(),
+        }
+    }
+
+    fn generation(generated_text: &str) -> Generation {
+        Generation {
+            generated_text: generated_text.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_get_typed_ahead() {
+        let ttl = Duration::from_secs(60);
+        let uri = "file:///test.py";
+        let mut cache = CompletionCache::default();
+        cache.resize(4);
+        cache.insert(
+            prompt_key(1, &prompt("prompt")),
+            1,
+            uri.to_owned(),
+            Rope::from_str("x = \nprint(x)\n"),
+            4,
+            vec![generation("foo(1)"), generation("bar()")],
+        );
+        assert_eq!(
+            cache
+                .get(prompt_key(1, &prompt("prompt")), ttl)
+                .map(|generations| generations.len()),
+            Some(2)
+        );
+        assert!(cache.get(prompt_key(2, &prompt("prompt")), ttl).is_none());
+        let with_suffix = Prompt {
+            suffix: Some("suffix".to_owned()),
+            ..prompt("prompt")
+        };
+        assert!(cache.get(prompt_key(1, &with_suffix), ttl).is_none());
+
+        let text = Rope::from_str("x = fo\nprint(x)\n");
+        let generations = cache.get_typed_ahead(1, uri, &text, 6, ttl).unwrap();
+        assert_eq!(generations.len(), 1);
+        assert_eq!(generations[0].generated_text, "o(1)");
+
+        // typed somewhere else
+        let text = Rope::from_str("x = fo\nprint(y)\n");
+        assert!(cache.get_typed_ahead(1, uri, &text, 6, ttl).is_none());
+        // does not match the generations
+        let text = Rope::from_str("x = baz\nprint(x)\n");
+        assert!(cache.get_typed_ahead(1, uri, &text, 7, ttl).is_none());
+        // different parameters
+        let text = Rope::from_str("x = fo\nprint(x)\n");
+        assert!(cache.get_typed_ahead(2, uri, &text, 6, ttl).is_none());
+        // expired
+        assert!(cache
+            .get_typed_ahead(1, uri, &text, 6, Duration::ZERO)
+            .is_none());
+        assert!(cache
+            .get(prompt_key(1, &prompt("prompt")), Duration::ZERO)
+            .is_none());
+    }
+}
@@ -0,0 +1,188 @@
+use custom_types::llm_ls::{CompletionType, SkipReason, SuppressParams};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tower_lsp::lsp_types::Position;
+use tracing::error;
+use tree_sitter::{Node, Query, QueryCursor};
+
+use crate::document::{get_parser, Document, PositionEncodingKind};
+use crate::language_id::LanguageId;
+use crate::postprocess::cursor_position;
+
+/// Tree-sitter queries deciding the completion type from the syntax nodes around the cursor,
+/// with the following captures:
+/// - `@comment` and `@string`, inside which completions are suppressed when configured
+/// - `@block_opener`, after which completions are multi line
+/// - `@arguments`, inside which completions are single line
+fn query_source(language_id: LanguageId) -> Option<&'static str> {
+    match language_id {
+        LanguageId::C => Some(include_str!("../queries/c.scm")),
+        LanguageId::Cpp => Some(include_str!("../queries/cpp.scm")),
+        LanguageId::CSharp => Some(include_str!("../queries/c_sharp.scm")),
+        LanguageId::Go => Some(include_str!("../queries/go.scm")),
+        LanguageId::Java => Some(include_str!("../queries/java.scm")),
+        LanguageId::JavaScript | LanguageId::JavaScriptReact => {
+            Some(include_str!("../queries/javascript.scm"))
+        }
+        LanguageId::Python => Some(include_str!("../queries/python.scm")),
+        LanguageId::Rust => Some(include_str!("../queries/rust.scm")),
+        LanguageId::TypeScript | LanguageId::TypeScriptReact => {
+            Some(include_str!("../queries/typescript.scm"))
+        }
+        _ => None,
+    }
+}
+
+fn compile_query(language_id: LanguageId) -> Option<Query> {
+    let source = query_source(language_id)?;
+    let language = get_parser(language_id).ok()?.language()?;
+    Query::new(language, source)
+        .map_err(|err| error!("invalid completion rules for {language_id}: {err}"))
+        .ok()
+}
+
+fn query(language_id: LanguageId) -> Option<&'static Query> {
+    static QUERIES: OnceLock<HashMap<LanguageId, Query>> = OnceLock::new();
+    QUERIES
+        .get_or_init(|| {
+            LanguageId::ALL
+                .into_iter()
+                .filter_map(|language_id| Some((language_id, compile_query(language_id)?)))
+                .collect()
+        })
+        .get(&language_id)
+}
+
+/// Whether the cursor is inside the comment, including at the end of a line comment.
+fn is_in_comment(document: &Document, node: Node, cursor: usize) -> bool {
+    let is_line_comment = || {
+        !document
+            .text
+            .byte_slice(node.byte_range())
+            .to_string()
+            .ends_with("*/")
+    };
+    node.start_byte() < cursor
+        && (cursor < node.end_byte() || (cursor == node.end_byte() && is_line_comment()))
+}
+
+/// Completion type set by the rules of the document's language, or the reason not to complete,
+/// if any rule applies at `position`.
+pub(crate) fn completion_type(
+    document: &Document,
+    position: Position,
+    suppress: &SuppressParams,
+    position_encoding: PositionEncodingKind,
+) -> Option<Result<CompletionType, SkipReason>> {
+    let query = query(document.language_id)?;
+    let tree = document.tree.as_ref()?;
+    let (char_idx, _) = cursor_position(&document.text, position, position_encoding)?;
+    let cursor = document.text.char_to_byte(char_idx);
+    // end of the code before the cursor, ignoring whitespace
+    let mut chars = document.text.chars_at(char_idx);
+    let mut prev_end = char_idx;
+    while chars.prev().is_some_and(char::is_whitespace) {
+        prev_end -= 1;
+    }
+    let prev_end = document.text.char_to_byte(prev_end);
+
+    let mut query_cursor = QueryCursor::new();
+    query_cursor.set_byte_range(prev_end.saturating_sub(1)..cursor + 1);
+    let text = |node: Node| {
+        document
+            .text
+            .byte_slice(node.byte_range())
+            .chunks()
+            .map(str::as_bytes)
+    };
+    let (mut multi_line, mut single_line) = (false, false);
+    for (query_match, capture_idx) in query_cursor.captures(query, tree.root_node(), text) {
+        let node = query_match.captures[capture_idx].node;
+        let inside = node.start_byte() < cursor && cursor < node.end_byte();
+        match query.capture_names()[query_match.captures[capture_idx].index as usize].as_str() {
+            "comment" if suppress.comments && is_in_comment(document, node, cursor) => {
+                return Some(Err(SkipReason::InsideComment));
+            }
+            "string" if suppress.strings && inside => return Some(Err(SkipReason::InsideString)),
+            "block_opener" if prev_end > 0 && node.end_byte() == prev_end => multi_line = true,
+            "arguments" if inside => single_line = true,
+            _ => (),
+        }
+    }
+    if multi_line {
+        Some(Ok(CompletionType::MultiLine))
+    } else if single_line {
+        Some(Ok(CompletionType::SingleLine))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_queries_compile() {
+        for language_id in LanguageId::ALL {
+            if query_source(language_id).is_some() {
+                assert!(
+                    compile_query(language_id).is_some(),
+                    "invalid completion rules for {language_id}"
+                );
+            }
+        }
+    }
+
+    async fn rule(
+        language_id: &str,
+        text: &str,
+        position: Position,
+    ) -> Option<Result<CompletionType, SkipReason>> {
+        let document = Document::open(language_id, text).await.unwrap();
+        completion_type(
+            &document,
+            position,
+            &SuppressParams {
+                strings: true,
+                comments: true,
+            },
+            PositionEncodingKind::Utf16,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_completion_type() {
+        let python = "def foo(a):\n    \n\nx = \"abc\"  # comment\nfoo(1, )\n";
+        assert_eq!(
+            rule("python", python, Position::new(1, 4)).await,
+            Some(Ok(CompletionType::MultiLine))
+        );
+        assert_eq!(
+            rule("python", python, Position::new(3, 6)).await,
+            Some(Err(SkipReason::InsideString))
+        );
+        assert_eq!(rule("python", python, Position::new(3, 9)).await, None);
+        assert_eq!(
+            rule("python", python, Position::new(3, 20)).await,
+            Some(Err(SkipReason::InsideComment))
+        );
+        assert_eq!(
+            rule("python", python, Position::new(4, 7)).await,
+            Some(Ok(CompletionType::SingleLine))
+        );
+
+        let rust = "fn main() {\n    \n}\n";
+        assert_eq!(
+            rule("rust", rust, Position::new(1, 4)).await,
+            Some(Ok(CompletionType::MultiLine))
+        );
+        let rust = "fn main() {\n    \n";
+        assert_eq!(
+            rule("rust", rust, Position::new(1, 4)).await,
+            Some(Ok(CompletionType::MultiLine))
+        );
+        let rust = "/* a */ fn main() {}\n";
+        assert_eq!(rule("rust", rust, Position::new(0, 7)).await, None);
+    }
+}
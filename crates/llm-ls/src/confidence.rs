@@ -0,0 +1,176 @@
+use std::ops::Range;
+
+use crate::{Generation, Token};
+
+/// Byte offset at which each token starts in the text the tokens spell out, along with the
+/// offset of the generated text in it, e.g. past the opening code fence of a chat answer. `None`
+/// when the tokens do not spell out the generated text.
+fn token_offsets(generated_text: &str, tokens: &[Token]) -> Option<(Vec<usize>, usize)> {
+    let spelled = tokens
+        .iter()
+        .map(|token| token.text.as_str())
+        .collect::<String>();
+    let base = if generated_text.starts_with(&spelled) {
+        0
+    } else {
+        spelled.find(generated_text)?
+    };
+    let offsets = tokens
+        .iter()
+        .scan(0, |offset, token| {
+            let start = *offset;
+            *offset += token.text.len();
+            Some(start)
+        })
+        .collect();
+    Some((offsets, base))
+}
+
+fn mean_probability(logprobs: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = logprobs.fold((0., 0), |(sum, count), logprob| (sum + logprob, count + 1));
+    (count > 0).then(|| (sum / count as f32).exp())
+}
+
+/// Geometric mean of the probabilities of the tokens overlapping `span`, a byte range of the
+/// generated text, or of all its tokens when they do not match the text.
+pub(crate) fn confidence(generation: &Generation, span: Range<usize>) -> Option<f32> {
+    let tokens = generation.tokens.as_deref()?;
+    match token_offsets(&generation.generated_text, tokens) {
+        Some((offsets, base)) => mean_probability(
+            offsets
+                .iter()
+                .zip(tokens)
+                .filter(|(&offset, token)| {
+                    offset < base + span.end && base + span.start < offset + token.text.len()
+                })
+                .map(|(_, token)| token.logprob),
+        ),
+        None => mean_probability(tokens.iter().map(|token| token.logprob)),
+    }
+}
+
+/// Cuts the generation before its first line whose confidence is below `min_confidence`, and
+/// drops it when that is the first line. Generations without log probabilities are kept as is.
+pub(crate) fn filter_low_confidence(
+    mut generation: Generation,
+    min_confidence: f32,
+) -> Option<Generation> {
+    let Some(tokens) = generation.tokens.as_deref() else {
+        return Some(generation);
+    };
+    let Some((offsets, base)) = token_offsets(&generation.generated_text, tokens) else {
+        return (confidence(&generation, 0..generation.generated_text.len())? >= min_confidence)
+            .then_some(generation);
+    };
+    let mut start = 0;
+    for line in generation.generated_text.split_inclusive('\n') {
+        let end = start + line.len();
+        let line_confidence = mean_probability(
+            offsets
+                .iter()
+                .zip(tokens)
+                .filter(|(&offset, _)| base + start <= offset && offset < base + end)
+                .map(|(_, token)| token.logprob),
+        );
+        if line_confidence.is_some_and(|confidence| confidence < min_confidence) {
+            if start == 0 {
+                return None;
+            }
+            let kept_tokens = offsets
+                .iter()
+                .take_while(|&&offset| offset < base + start)
+                .count();
+            generation.generated_text.truncate(start);
+            generation.generated_text = generation.generated_text.trim_end().to_owned();
+            if let Some(tokens) = &mut generation.tokens {
+                tokens.truncate(kept_tokens);
+            }
+            return Some(generation);
+        }
+        start = end;
+    }
+    Some(generation)
+}
+
+/// Rest of the generation after `prefix`, along with the tokens that follow it.
+pub(crate) fn strip_prefix(generation: &Generation, prefix: &str) -> Option<Generation> {
+    let generated_text = generation.generated_text.strip_prefix(prefix)?.to_owned();
+    let tokens = generation.tokens.as_deref().and_then(|tokens| {
+        let (offsets, base) = token_offsets(&generation.generated_text, tokens)?;
+        let skipped = offsets
+            .iter()
+            .take_while(|&&offset| offset < base + prefix.len())
+            .count();
+        Some(tokens[skipped..].to_vec()).filter(|tokens| !tokens.is_empty())
+    });
+    Some(Generation {
+        generated_text,
+        tokens,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn generation(tokens: &[(&str, f32)]) -> Generation {
+        Generation {
+            generated_text: tokens.iter().map(|(text, _)| *text).collect(),
+            tokens: Some(
+                tokens
+                    .iter()
+                    .map(|&(text, logprob)| Token {
+                        text: text.to_owned(),
+                        logprob,
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_filter_low_confidence() {
+        let gen = generation(&[("return", -0.1), (" a", -0.1), ("\n", -0.1), ("foo", -3.)]);
+        let filtered = filter_low_confidence(gen, 0.5).unwrap();
+        assert_eq!(filtered.generated_text, "return a");
+        assert_eq!(filtered.tokens.as_ref().map(Vec::len), Some(3));
+        assert!(confidence(&filtered, 0..filtered.generated_text.len()).unwrap() > 0.9);
+
+        let gen = generation(&[("foo", -3.), ("\n", -0.1), ("return", -0.1)]);
+        assert!(filter_low_confidence(gen, 0.5).is_none());
+
+        let gen = Generation {
+            generated_text: "foo".to_owned(),
+            tokens: None,
+        };
+        assert!(filter_low_confidence(gen, 0.5).is_some());
+    }
+
+    #[test]
+    fn test_confidence() {
+        let gen = generation(&[("<fim_middle>", -3.), ("return", -0.1), (" a", -0.1)]);
+        let confident = confidence(&gen, 12..gen.generated_text.len()).unwrap();
+        assert!(confident > 0.9);
+        assert!(confidence(&gen, 0..gen.generated_text.len()).unwrap() < confident);
+
+        // the tokens of a chat answer spell out the fences the generated text was stripped of
+        let mut gen = generation(&[
+            ("```\n", -3.),
+            ("return", -0.1),
+            (" a", -0.1),
+            ("\n```", -3.),
+        ]);
+        gen.generated_text = "return a".to_owned();
+        assert!(confidence(&gen, 0..gen.generated_text.len()).unwrap() > 0.9);
+        assert!(confidence(&gen, 0..6).unwrap() > 0.9);
+    }
+
+    #[test]
+    fn test_strip_prefix() {
+        let gen = generation(&[("foo", -1.), ("(", -0.5), (")", -0.2)]);
+        let rest = strip_prefix(&gen, "fo").unwrap();
+        assert_eq!(rest.generated_text, "o()");
+        assert_eq!(rest.tokens.map(|tokens| tokens.len()), Some(2));
+        assert!(strip_prefix(&gen, "bar").is_none());
+    }
+}
@@ -0,0 +1,157 @@
+use custom_types::llm_ls::LlmLsConfigOverrides;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::error::Result;
+
+pub(crate) const PROJECT_CONFIG_FILE_NAME: &str = ".llm-ls.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProjectConfigFile {
+    #[serde(flatten)]
+    overrides: LlmLsConfigOverrides,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+/// Configuration read from a `.llm-ls.toml` file, applying to every document under the
+/// directory containing it.
+#[derive(Debug)]
+pub(crate) struct ProjectConfig {
+    pub(crate) overrides: LlmLsConfigOverrides,
+    /// Directory containing the configuration file
+    root: PathBuf,
+    /// Paths for which completions are disabled, relative to `root`
+    exclude: GlobSet,
+}
+
+impl ProjectConfig {
+    fn parse(root: PathBuf, content: &str) -> Result<Self> {
+        let file: ProjectConfigFile = toml::from_str(content)?;
+        let mut exclude = GlobSetBuilder::new();
+        for pattern in &file.exclude {
+            exclude.add(Glob::new(pattern)?);
+        }
+        Ok(Self {
+            overrides: file.overrides,
+            root,
+            exclude: exclude.build()?,
+        })
+    }
+
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.root)
+            .is_ok_and(|path| self.exclude.is_match(path))
+    }
+}
+
+/// Cache of the project configurations found on disk, keyed by directory. Directories without
+/// a configuration file are cached as well to avoid hitting the file system on every request.
+#[derive(Default)]
+pub(crate) struct ProjectConfigs {
+    configs: HashMap<PathBuf, Option<Arc<ProjectConfig>>>,
+}
+
+impl ProjectConfigs {
+    /// Finds the configuration closest to `path`, walking up its ancestors until one of the
+    /// `workspace_roots` or the root of the file system is reached. The lock is only held to look
+    /// up and insert cache entries, not while reading files.
+    pub(crate) async fn find(
+        configs: &RwLock<Self>,
+        path: &Path,
+        workspace_roots: &[PathBuf],
+    ) -> Option<Arc<ProjectConfig>> {
+        for dir in path.ancestors().skip(1) {
+            let cached = configs.read().await.configs.get(dir).cloned();
+            let config = match cached {
+                Some(config) => config,
+                None => {
+                    let config = load(dir).await;
+                    configs
+                        .write()
+                        .await
+                        .configs
+                        .insert(dir.to_path_buf(), config.clone());
+                    config
+                }
+            };
+            if config.is_some() {
+                return config;
+            }
+            if workspace_roots.iter().any(|root| root == dir) {
+                break;
+            }
+        }
+        None
+    }
+
+    /// Forgets the configuration read from `config_path`, to be called when the file changed.
+    pub(crate) fn invalidate(&mut self, config_path: &Path) {
+        if let Some(dir) = config_path.parent() {
+            self.configs.remove(dir);
+        }
+    }
+}
+
+async fn load(dir: &Path) -> Option<Arc<ProjectConfig>> {
+    let config_path = dir.join(PROJECT_CONFIG_FILE_NAME);
+    let content = tokio::fs::read_to_string(&config_path).await.ok()?;
+    match ProjectConfig::parse(dir.to_path_buf(), &content) {
+        Ok(config) => {
+            info!("loaded project configuration {}", config_path.display());
+            Some(Arc::new(config))
+        }
+        Err(err) => {
+            error!("error loading {}: {err}", config_path.display());
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use custom_types::llm_ls::Backend;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_project_config() {
+        let config = ProjectConfig::parse(
+            PathBuf::from("/repo"),
+            r#"
+model = "bigcode/starcoder2-3b"
+backend = "tgi"
+url = "http://localhost:8080"
+contextWindow = 4096
+exclude = ["vendor/**", "**/*.min.js"]
+
+[fim]
+enabled = true
+prefix = "<fim_prefix>"
+middle = "<fim_middle>"
+suffix = "<fim_suffix>"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.overrides.model.as_deref(),
+            Some("bigcode/starcoder2-3b")
+        );
+        assert!(matches!(
+            config.overrides.backend,
+            Some(Backend::Tgi { .. })
+        ));
+        assert_eq!(config.overrides.context_window, Some(4096));
+        assert!(config.overrides.fim.as_ref().is_some_and(|fim| fim.enabled));
+        assert!(config.is_excluded(Path::new("/repo/vendor/lib/a.rs")));
+        assert!(config.is_excluded(Path::new("/repo/web/app.min.js")));
+        assert!(!config.is_excluded(Path::new("/repo/src/main.rs")));
+        assert!(!config.is_excluded(Path::new("/other/vendor/a.rs")));
+    }
+}
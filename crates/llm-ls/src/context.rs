@@ -0,0 +1,151 @@
+use custom_types::llm_ls::CrossFileContextParams;
+use ropey::Rope;
+use std::collections::HashSet;
+
+use crate::document::Document;
+
+/// Piece of another document deemed relevant to the code around the cursor.
+#[derive(Clone, Debug)]
+pub(crate) struct Snippet {
+    pub(crate) path: String,
+    pub(crate) content: String,
+    pub(crate) score: f32,
+}
+
+/// Splits `text` in identifiers, leaving out punctuation and numbers.
+pub(crate) fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty() && !word.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+fn identifiers(text: &str) -> HashSet<&str> {
+    words(text).collect()
+}
+
+fn jaccard_similarity(a: &HashSet<&str>, b: &HashSet<&str>) -> f32 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.;
+    }
+    a.intersection(b).count() as f32 / union as f32
+}
+
+/// Finds the window of `window_size` lines of `text` whose identifiers overlap the most with
+/// `query`, sliding the window by half its size.
+fn best_window(text: &Rope, query: &HashSet<&str>, window_size: usize) -> Option<(f32, String)> {
+    let line_count = text.len_lines();
+    let stride = (window_size / 2).max(1);
+    let mut best: Option<(f32, String)> = None;
+    let mut start = 0;
+    loop {
+        let end = (start + window_size).min(line_count);
+        let window = text
+            .slice(text.line_to_char(start)..text.line_to_char(end))
+            .to_string();
+        let score = jaccard_similarity(query, &identifiers(&window));
+        if best
+            .as_ref()
+            .is_none_or(|(best_score, _)| score > *best_score)
+        {
+            best = Some((score, window));
+        }
+        if end >= line_count {
+            break;
+        }
+        start += stride;
+    }
+    best
+}
+
+/// Selects the snippets of `documents` most similar to `query`, at most one per document, sorted
+/// by decreasing similarity.
+pub(crate) fn gather_snippets<'a>(
+    query: &str,
+    documents: impl Iterator<Item = (String, &'a Document)>,
+    params: &CrossFileContextParams,
+) -> Vec<Snippet> {
+    let query = identifiers(query);
+    if query.is_empty() {
+        return vec![];
+    }
+    let mut snippets = documents
+        .filter_map(|(path, document)| {
+            let (score, content) = best_window(&document.text, &query, params.window_size)?;
+            (score > 0.).then_some(Snippet {
+                path,
+                content,
+                score,
+            })
+        })
+        .collect::<Vec<_>>();
+    snippets.sort_by(|a, b| b.score.total_cmp(&a.score));
+    snippets.truncate(params.max_snippets);
+    snippets
+}
+
+/// Renders a snippet preceded by the path of the file it comes from, either in a comment or
+/// after the model's file separator token.
+pub(crate) fn format_snippet(
+    snippet: &Snippet,
+    line_comment: &str,
+    file_separator: Option<&str>,
+) -> String {
+    let mut formatted = match file_separator {
+        Some(file_separator) => format!("{file_separator}{}\n{}", snippet.path, snippet.content),
+        None => format!("{line_comment} Path: {}\n{}", snippet.path, snippet.content),
+    };
+    if !formatted.ends_with('\n') {
+        formatted.push('\n');
+    }
+    formatted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_gather_snippets() {
+        let models = Document::open(
+            "python",
+            "import os\n\nclass User:\n    def __init__(self, name, email):\n        self.name = name\n        self.email = email\n",
+        )
+        .await
+        .unwrap();
+        let unrelated = Document::open("python", "def add(a, b):\n    return a + b\n")
+            .await
+            .unwrap();
+        let documents = vec![
+            ("models.py".to_owned(), &models),
+            ("maths.py".to_owned(), &unrelated),
+        ];
+        let params = CrossFileContextParams {
+            enabled: true,
+            max_snippets: 4,
+            window_size: 4,
+            max_tokens: 512,
+        };
+
+        let snippets = gather_snippets(
+            "user = User(name, email)\nprint(user.",
+            documents.into_iter(),
+            &params,
+        );
+
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].path, "models.py");
+        assert!(snippets[0]
+            .content
+            .contains("def __init__(self, name, email):"));
+        assert_eq!(
+            format_snippet(&snippets[0], "#", None).lines().next(),
+            Some("# Path: models.py")
+        );
+        assert_eq!(
+            format_snippet(&snippets[0], "#", Some("<file_sep>"))
+                .lines()
+                .next(),
+            Some("<file_sep>models.py")
+        );
+    }
+}
@@ -1,11 +1,13 @@
 use ropey::{Rope, RopeSlice};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use tokenizers::Tokenizer;
 use tower_lsp::lsp_types::{Position, TextDocumentContentChangeEvent};
 use tree_sitter::{InputEdit, Parser, Point, Tree};
 
 use crate::error::{Error, Result};
 use crate::language_id::LanguageId;
 
-fn get_parser(language_id: LanguageId) -> Result<Parser> {
+pub(crate) fn get_parser(language_id: LanguageId) -> Result<Parser> {
     match language_id {
         LanguageId::Bash => {
             let mut parser = Parser::new();
@@ -177,11 +179,32 @@ impl PositionEncodingKind {
     }
 }
 
+/// Number of tokens of each line of a document, computed as they are requested
+#[derive(Default)]
+struct LineTokenCounts {
+    /// Tokenizer the counts were computed with
+    tokenizer: Weak<Tokenizer>,
+    counts: Vec<Option<usize>>,
+}
+
+impl LineTokenCounts {
+    /// Forgets the counts of the lines `start..=old_end`, replaced by `new_line_count` lines.
+    fn invalidate(&mut self, start: usize, old_end: usize, new_line_count: usize) {
+        if old_end < self.counts.len() {
+            self.counts
+                .splice(start..=old_end, std::iter::repeat_n(None, new_line_count));
+        } else {
+            self.counts.clear();
+        }
+    }
+}
+
 pub(crate) struct Document {
     pub(crate) language_id: LanguageId,
     pub(crate) text: Rope,
     parser: Parser,
     pub(crate) tree: Option<Tree>,
+    line_token_counts: Mutex<LineTokenCounts>,
 }
 
 impl Document {
@@ -195,9 +218,40 @@ impl Document {
             text: rope,
             parser,
             tree,
+            line_token_counts: Mutex::default(),
         })
     }
 
+    /// Number of tokens of the line at `line_idx`, line ending included, cached until the line is
+    /// edited.
+    pub(crate) fn line_token_count(
+        &self,
+        tokenizer: &Arc<Tokenizer>,
+        line_idx: usize,
+    ) -> Result<usize> {
+        let mut cache = self
+            .line_token_counts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let len_lines = self.text.len_lines();
+        if !cache.tokenizer.ptr_eq(&Arc::downgrade(tokenizer)) || cache.counts.len() != len_lines {
+            cache.tokenizer = Arc::downgrade(tokenizer);
+            cache.counts = vec![None; len_lines];
+        }
+        let line = self
+            .text
+            .get_line(line_idx)
+            .ok_or(Error::OutOfBoundLine(line_idx, len_lines))?;
+        match cache.counts[line_idx] {
+            Some(count) => Ok(count),
+            None => {
+                let count = tokenizer.encode(line.to_string(), false)?.len();
+                cache.counts[line_idx] = Some(count);
+                Ok(count)
+            }
+        }
+    }
+
     pub(crate) fn apply_content_change(
         &mut self,
         change: &TextDocumentContentChangeEvent,
@@ -296,9 +350,19 @@ impl Document {
                     },
                 };
 
+                let old_len_lines = self.text.len_lines();
                 self.text
                     .remove(change_start_doc_char_idx..change_end_doc_char_idx);
                 self.text.insert(change_start_doc_char_idx, &change.text);
+                let replaced_line_count = change_end_line_idx - change_start_line_idx + 1;
+                self.line_token_counts
+                    .get_mut()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .invalidate(
+                        change_start_line_idx,
+                        change_end_line_idx,
+                        (replaced_line_count + self.text.len_lines()).saturating_sub(old_len_lines),
+                    );
 
                 if let Some(tree) = &mut self.tree {
                     // 6. Compute the byte index into the new end line where the
@@ -346,6 +410,11 @@ impl Document {
             None => {
                 self.text = Rope::from_str(&change.text);
                 self.tree = self.parser.parse(&change.text, None);
+                self.line_token_counts
+                    .get_mut()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .counts
+                    .clear();
 
                 Ok(())
             }
@@ -417,6 +486,28 @@ mod test {
         assert_eq!(doc.text.to_string(), "");
     }
 
+    #[tokio::test]
+    async fn test_line_token_counts_invalidation() {
+        let mut doc = Document::open("unknown", "a\nb\nc\n").await.unwrap();
+        doc.line_token_counts.get_mut().unwrap().counts = vec![Some(1); 4];
+
+        doc.apply_content_change(new_change!(1, 0, 1, 0, "x\ny"), PositionEncodingKind::Utf16)
+            .unwrap();
+        assert_eq!(doc.text.to_string(), "a\nx\nyb\nc\n");
+        assert_eq!(
+            doc.line_token_counts.get_mut().unwrap().counts,
+            vec![Some(1), None, None, Some(1), Some(1)]
+        );
+
+        doc.apply_content_change(new_change!(0, 1, 2, 0, ""), PositionEncodingKind::Utf16)
+            .unwrap();
+        assert_eq!(doc.text.to_string(), "ayb\nc\n");
+        assert_eq!(
+            doc.line_token_counts.get_mut().unwrap().counts,
+            vec![None, Some(1), Some(1)]
+        );
+    }
+
     #[tokio::test]
     async fn test_text_document_apply_content_change_no_range() {
         let mut rope = Rope::from_str(
@@ -441,7 +532,7 @@ mod test {
         ).is_ok());
         assert_eq!(doc.text.to_string(), rope.to_string());
 
-        let tree = parser.parse(&rope.to_string(), None).unwrap();
+        let tree = parser.parse(rope.to_string(), None).unwrap();
 
         assert!(nodes_are_equal_recursive(
             &doc.tree.as_ref().unwrap().root_node(),
@@ -461,7 +552,7 @@ mod test {
         rope = Rope::from_str("let a = '🥸 你好，😊';");
         assert_eq!(doc.text.to_string(), rope.to_string());
 
-        let tree = parser.parse(&rope.to_string(), None).unwrap();
+        let tree = parser.parse(rope.to_string(), None).unwrap();
 
         assert!(nodes_are_equal_recursive(
             &doc.tree.as_ref().unwrap().root_node(),
@@ -15,8 +15,12 @@ pub(crate) fn internal_error<E: Display>(err: E) -> LspError {
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error("anthropic error: {0}")]
+    Anthropic(crate::backend::AnthropicError),
     #[error("no encoding kind provided by the client")]
     EncodingKindMissing,
+    #[error("glob error: {0}")]
+    Glob(#[from] globset::Error),
     #[error("http error: {0}")]
     Http(#[from] reqwest::Error),
     #[error("io error: {0}")]
@@ -34,7 +38,9 @@ pub enum Error {
     #[error("invalid tokenizer path")]
     InvalidTokenizerPath,
     #[error("llama.cpp error: {0}")]
-    LlamaCpp(crate::backend::APIError),
+    LlamaCpp(crate::backend::LlamaCppError),
+    #[error("mistral error: {0}")]
+    Mistral(crate::backend::MistralError),
     #[error("ollama error: {0}")]
     Ollama(crate::backend::APIError),
     #[error("openai error: {0}")]
@@ -45,12 +51,16 @@ pub enum Error {
     OutOfBoundLine(usize, usize),
     #[error("slice out of bounds: {0}..{1}")]
     OutOfBoundSlice(usize, usize),
+    #[error("rate limited by the backend")]
+    RateLimited,
     #[error("rope error: {0}")]
     Rope(#[from] ropey::Error),
     #[error("serde json error: {0}")]
     SerdeJson(#[from] serde_json::Error),
     #[error("tgi error: {0}")]
     Tgi(crate::backend::APIError),
+    #[error("toml error: {0}")]
+    Toml(#[from] toml::de::Error),
     #[error("tree-sitter parse error: timeout possibly exceeded")]
     TreeSitterParsing,
     #[error("tree-sitter language error: {0}")]
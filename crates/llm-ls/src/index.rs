@@ -0,0 +1,453 @@
+use custom_types::llm_ls::WorkspaceIndexParams;
+use ignore::gitignore::Gitignore;
+use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+use crate::context::{words, Snippet};
+use crate::document::get_parser;
+use crate::error::Result;
+use crate::language_id::LanguageId;
+
+/// BM25 term frequency saturation
+const K1: f32 = 1.2;
+/// BM25 length normalization
+const B: f32 = 0.75;
+
+fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    words(text)
+        .filter(|word| word.len() > 1)
+        .map(str::to_lowercase)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Chunk {
+    start_line: usize,
+    content: String,
+    term_frequencies: HashMap<String, u32>,
+    length: u32,
+}
+
+impl Chunk {
+    fn new(start_line: usize, content: String) -> Self {
+        let mut term_frequencies = HashMap::new();
+        let mut length = 0;
+        for term in terms(&content) {
+            *term_frequencies.entry(term).or_default() += 1;
+            length += 1;
+        }
+        Self {
+            start_line,
+            content,
+            term_frequencies,
+            length,
+        }
+    }
+}
+
+/// Splits a file along its top-level syntax nodes, merging consecutive nodes into chunks of at
+/// most `max_chunk_lines` lines and splitting the nodes that are longer than that.
+fn chunk_file(text: &str, language_id: LanguageId, max_chunk_lines: usize) -> Vec<Chunk> {
+    let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+    let max_chunk_lines = max_chunk_lines.max(1);
+    let tree = get_parser(language_id)
+        .ok()
+        .and_then(|mut parser| parser.parse(text, None));
+    let nodes = match &tree {
+        Some(tree) => {
+            let root = tree.root_node();
+            let mut cursor = root.walk();
+            let nodes = root
+                .named_children(&mut cursor)
+                .map(|node| (node.start_position().row, node.end_position().row + 1))
+                .collect();
+            nodes
+        }
+        None => vec![(0, lines.len())],
+    };
+
+    let mut ranges = vec![];
+    let mut current: Option<(usize, usize)> = None;
+    for (mut start, end) in nodes {
+        let end = end.min(lines.len());
+        if let Some((current_start, current_end)) = current {
+            if end.saturating_sub(current_start) <= max_chunk_lines {
+                current = Some((current_start, end.max(current_end)));
+                continue;
+            }
+            ranges.push((current_start, current_end));
+            start = start.max(current_end);
+        }
+        while end.saturating_sub(start) > max_chunk_lines {
+            ranges.push((start, start + max_chunk_lines));
+            start += max_chunk_lines;
+        }
+        current = Some((start, end));
+    }
+    ranges.extend(current);
+
+    ranges
+        .into_iter()
+        .filter(|(start, end)| start < end)
+        .map(|(start, end)| Chunk::new(start, lines[start..end].concat()))
+        .filter(|chunk| chunk.length > 0)
+        .collect()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct IndexedFile {
+    modified: SystemTime,
+    chunks: Vec<Chunk>,
+}
+
+/// BM25 index of the files of a workspace folder, persisted in the cache directory and updated
+/// incrementally as files change.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct WorkspaceIndex {
+    root: PathBuf,
+    /// Indexed files, keyed by path relative to `root`
+    files: HashMap<PathBuf, IndexedFile>,
+    document_frequencies: HashMap<String, u32>,
+    chunk_count: u32,
+    total_length: u64,
+}
+
+/// Change to a file of the index, read from disk without borrowing the index so that it can be
+/// done outside of its lock.
+pub(crate) enum FileUpdate {
+    Unchanged,
+    Removed(PathBuf),
+    Modified(PathBuf, IndexedFile),
+}
+
+/// Whether `relative_path` is left out of the index, being hidden or ignored by one of the
+/// `.ignore` or `.gitignore` files between it and `root`, as when walking the workspace folder.
+fn is_ignored(root: &Path, relative_path: &Path) -> bool {
+    if relative_path
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with('.'))
+    {
+        return true;
+    }
+    let path = root.join(relative_path);
+    // the closest ignore file deciding on the path wins
+    for dir in path.ancestors().skip(1) {
+        for file_name in [".ignore", ".gitignore"] {
+            let ignore_file = dir.join(file_name);
+            if !ignore_file.is_file() {
+                continue;
+            }
+            let (gitignore, _) = Gitignore::new(ignore_file);
+            let matched = gitignore.matched_path_or_any_parents(&path, false);
+            if !matched.is_none() {
+                return matched.is_ignore();
+            }
+        }
+        if dir == root {
+            break;
+        }
+    }
+    false
+}
+
+/// Reads the change to `relative_path` after it was created, modified or deleted. `indexed` is
+/// the modification time of the file when it was last indexed.
+pub(crate) fn read_update(
+    root: &Path,
+    relative_path: PathBuf,
+    indexed: Option<SystemTime>,
+    params: &WorkspaceIndexParams,
+) -> FileUpdate {
+    if is_ignored(root, &relative_path) || !root.join(&relative_path).is_file() {
+        return FileUpdate::Removed(relative_path);
+    }
+    read_file(root, relative_path, indexed, params)
+}
+
+/// Reads `relative_path` from disk and chunks it, unless it was not modified since `indexed`.
+fn read_file(
+    root: &Path,
+    relative_path: PathBuf,
+    indexed: Option<SystemTime>,
+    params: &WorkspaceIndexParams,
+) -> FileUpdate {
+    let path = root.join(&relative_path);
+    let language_id = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(LanguageId::from_extension)
+        .unwrap_or(LanguageId::Unknown);
+    if matches!(language_id, LanguageId::Unknown) {
+        return FileUpdate::Unchanged;
+    }
+    let Ok(metadata) = std::fs::metadata(&path) else {
+        return FileUpdate::Unchanged;
+    };
+    if metadata.len() > params.max_file_size {
+        return FileUpdate::Removed(relative_path);
+    }
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    if indexed == Some(modified) {
+        return FileUpdate::Unchanged;
+    }
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return FileUpdate::Unchanged;
+    };
+    debug!("indexing {}", path.display());
+    let chunks = chunk_file(&text, language_id, params.max_chunk_lines);
+    FileUpdate::Modified(relative_path, IndexedFile { modified, chunks })
+}
+
+impl WorkspaceIndex {
+    fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            files: HashMap::new(),
+            document_frequencies: HashMap::new(),
+            chunk_count: 0,
+            total_length: 0,
+        }
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn cache_path(cache_dir: &Path, root: &Path) -> PathBuf {
+        let name = root
+            .to_string_lossy()
+            .replace(|c: char| !c.is_alphanumeric(), "_");
+        cache_dir.join("index").join(format!("{name}.json"))
+    }
+
+    /// Loads the index of `root` from the cache directory, or creates an empty one.
+    pub(crate) fn load(cache_dir: &Path, root: PathBuf) -> Self {
+        let cache_path = Self::cache_path(cache_dir, &root);
+        std::fs::read(&cache_path)
+            .ok()
+            .and_then(|content| serde_json::from_slice::<WorkspaceIndex>(&content).ok())
+            .filter(|index| index.root == root)
+            .unwrap_or_else(|| Self::new(root))
+    }
+
+    pub(crate) fn save(&self, cache_dir: &Path) -> Result<()> {
+        let cache_path = Self::cache_path(cache_dir, &self.root);
+        if let Some(parent) = cache_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(cache_path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    /// Walks the workspace folder, respecting `.gitignore` files, to index new and modified files
+    /// and forget the deleted ones.
+    pub(crate) fn refresh(&mut self, params: &WorkspaceIndexParams) {
+        let mut seen = HashSet::new();
+        for entry in WalkBuilder::new(&self.root)
+            .require_git(false)
+            .build()
+            .flatten()
+        {
+            if !entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file())
+            {
+                continue;
+            }
+            let Ok(relative_path) = entry.path().strip_prefix(&self.root) else {
+                continue;
+            };
+            let relative_path = relative_path.to_path_buf();
+            seen.insert(relative_path.clone());
+            let indexed = self.files.get(&relative_path).map(|file| file.modified);
+            let update = read_file(&self.root, relative_path, indexed, params);
+            self.apply(update);
+        }
+        let deleted = self
+            .files
+            .keys()
+            .filter(|path| !seen.contains(*path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in deleted {
+            self.remove_file(&path);
+        }
+        info!(
+            root = %self.root.display(),
+            files = self.files.len(),
+            chunks = self.chunk_count,
+            "indexed workspace folder"
+        );
+    }
+
+    /// Path of `path` relative to the root when it belongs to this workspace folder, along with
+    /// the modification time of the file when it was last indexed.
+    pub(crate) fn locate(&self, path: &Path) -> Option<(PathBuf, Option<SystemTime>)> {
+        let relative_path = path.strip_prefix(&self.root).ok()?.to_path_buf();
+        let indexed = self.files.get(&relative_path).map(|file| file.modified);
+        Some((relative_path, indexed))
+    }
+
+    pub(crate) fn apply(&mut self, update: FileUpdate) {
+        match update {
+            FileUpdate::Unchanged => (),
+            FileUpdate::Removed(relative_path) => self.remove_file(&relative_path),
+            FileUpdate::Modified(relative_path, file) => {
+                self.remove_file(&relative_path);
+                for chunk in &file.chunks {
+                    for term in chunk.term_frequencies.keys() {
+                        *self.document_frequencies.entry(term.clone()).or_default() += 1;
+                    }
+                    self.chunk_count += 1;
+                    self.total_length += chunk.length as u64;
+                }
+                self.files.insert(relative_path, file);
+            }
+        }
+    }
+
+    fn remove_file(&mut self, relative_path: &Path) {
+        let Some(file) = self.files.remove(relative_path) else {
+            return;
+        };
+        for chunk in &file.chunks {
+            for term in chunk.term_frequencies.keys() {
+                if let Some(frequency) = self.document_frequencies.get_mut(term) {
+                    *frequency -= 1;
+                    if *frequency == 0 {
+                        self.document_frequencies.remove(term);
+                    }
+                }
+            }
+            self.chunk_count -= 1;
+            self.total_length -= chunk.length as u64;
+        }
+    }
+
+    /// Ranks the chunks of the index against `query` with BM25, leaving out the files in
+    /// `excluded`.
+    pub(crate) fn search(
+        &self,
+        query: &str,
+        top_k: usize,
+        excluded: &HashSet<PathBuf>,
+    ) -> Vec<Snippet> {
+        if self.chunk_count == 0 {
+            return vec![];
+        }
+        let query = terms(query).collect::<HashSet<_>>();
+        let chunk_count = self.chunk_count as f32;
+        let average_length = self.total_length as f32 / chunk_count;
+        let idfs = query
+            .iter()
+            .filter_map(|term| {
+                let frequency = *self.document_frequencies.get(term)? as f32;
+                let idf = ((chunk_count - frequency + 0.5) / (frequency + 0.5) + 1.).ln();
+                Some((term, idf))
+            })
+            .collect::<Vec<_>>();
+
+        let mut snippets = vec![];
+        for (path, file) in &self.files {
+            if excluded.contains(&self.root.join(path)) {
+                continue;
+            }
+            for chunk in &file.chunks {
+                let length_norm = 1. - B + B * chunk.length as f32 / average_length;
+                let score = idfs
+                    .iter()
+                    .filter_map(|(term, idf)| {
+                        let frequency = *chunk.term_frequencies.get(*term)? as f32;
+                        Some(idf * frequency * (K1 + 1.) / (frequency + K1 * length_norm))
+                    })
+                    .sum::<f32>();
+                if score > 0. {
+                    snippets.push(Snippet {
+                        path: path.display().to_string(),
+                        content: chunk.content.clone(),
+                        score,
+                    });
+                }
+            }
+        }
+        snippets.sort_by(|a, b| b.score.total_cmp(&a.score));
+        snippets.truncate(top_k);
+        snippets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chunk_file() {
+        let text = "import os\nimport sys\n\n\ndef foo():\n    return os.getcwd()\n\n\nclass Bar:\n    def baz(self):\n        return sys.argv\n";
+        let chunks = chunk_file(text, LanguageId::Python, 3);
+        let starts = chunks
+            .iter()
+            .map(|chunk| chunk.start_line)
+            .collect::<Vec<_>>();
+        assert_eq!(starts, vec![0, 4, 8]);
+        assert!(chunks[1].content.starts_with("def foo():"));
+        assert!(chunks[2].content.starts_with("class Bar:"));
+    }
+
+    fn update(index: &mut WorkspaceIndex, path: &Path, params: &WorkspaceIndexParams) {
+        let (relative_path, indexed) = index.locate(path).unwrap();
+        let update = read_update(&index.root, relative_path, indexed, params);
+        index.apply(update);
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = std::env::temp_dir().join(format!("llm-ls-index-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join("src/db.py"),
+            "def connect(url):\n    return Connection(url)\n\n\ndef close(connection):\n    connection.close()\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("src/maths.py"),
+            "def add(a, b):\n    return a + b\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "connection url").unwrap();
+
+        let params = WorkspaceIndexParams::default();
+        let mut index = WorkspaceIndex::load(&dir, dir.clone());
+        index.refresh(&params);
+        assert_eq!(index.files.len(), 2);
+
+        let snippets = index.search("conn = connect(url)", 1, &HashSet::new());
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(
+            snippets[0].path,
+            Path::new("src").join("db.py").display().to_string()
+        );
+        assert!(snippets[0].content.starts_with("def connect(url):"));
+
+        let excluded = HashSet::from([dir.join("src/db.py")]);
+        assert!(index.search("connect", 1, &excluded).is_empty());
+
+        std::fs::remove_file(dir.join("src/db.py")).unwrap();
+        update(&mut index, &dir.join("src/db.py"), &params);
+        assert!(index.search("connect", 1, &HashSet::new()).is_empty());
+        assert_eq!(index.chunk_count, 1);
+
+        // ignored by a nested ignore file, as when walking the workspace folder
+        std::fs::write(dir.join("src/.gitignore"), "generated_*.py\n").unwrap();
+        std::fs::write(dir.join("src/generated_db.py"), "def connect(url): ...\n").unwrap();
+        update(&mut index, &dir.join("src/generated_db.py"), &params);
+        assert!(index.search("connect", 1, &HashSet::new()).is_empty());
+        std::fs::write(dir.join("src/db.py"), "def connect(url): ...\n").unwrap();
+        update(&mut index, &dir.join("src/db.py"), &params);
+        assert_eq!(index.search("connect", 1, &HashSet::new()).len(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
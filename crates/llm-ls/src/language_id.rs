@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
-#[derive(Clone, Copy, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub(crate) enum LanguageId {
     Bash,
     C,
@@ -62,6 +62,128 @@ impl fmt::Display for LanguageId {
     }
 }
 
+impl LanguageId {
+    pub(crate) const ALL: [Self; 25] = [
+        Self::Bash,
+        Self::C,
+        Self::Cpp,
+        Self::CSharp,
+        Self::Elixir,
+        Self::Erlang,
+        Self::Go,
+        Self::Html,
+        Self::Java,
+        Self::JavaScript,
+        Self::JavaScriptReact,
+        Self::Json,
+        Self::Kotlin,
+        Self::Lua,
+        Self::Markdown,
+        Self::ObjectiveC,
+        Self::Python,
+        Self::R,
+        Self::Ruby,
+        Self::Rust,
+        Self::Scala,
+        Self::Swift,
+        Self::TypeScript,
+        Self::TypeScriptReact,
+        Self::Unknown,
+    ];
+
+    pub(crate) fn from_extension(extension: &str) -> Self {
+        match extension {
+            "sh" | "bash" => Self::Bash,
+            "c" | "h" => Self::C,
+            "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => Self::Cpp,
+            "cs" => Self::CSharp,
+            "ex" | "exs" => Self::Elixir,
+            "erl" | "hrl" => Self::Erlang,
+            "go" => Self::Go,
+            "htm" | "html" => Self::Html,
+            "java" => Self::Java,
+            "cjs" | "js" | "mjs" => Self::JavaScript,
+            "jsx" => Self::JavaScriptReact,
+            "json" => Self::Json,
+            "kt" | "kts" => Self::Kotlin,
+            "lua" => Self::Lua,
+            "md" => Self::Markdown,
+            "m" => Self::ObjectiveC,
+            "py" | "pyi" => Self::Python,
+            "r" | "R" => Self::R,
+            "rb" => Self::Ruby,
+            "rs" => Self::Rust,
+            "scala" => Self::Scala,
+            "swift" => Self::Swift,
+            "ts" | "mts" | "cts" => Self::TypeScript,
+            "tsx" => Self::TypeScriptReact,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Token starting a line comment, falling back to `#` for languages without line comments.
+    pub(crate) fn line_comment(&self) -> &'static str {
+        match self {
+            Self::C
+            | Self::Cpp
+            | Self::CSharp
+            | Self::Go
+            | Self::Java
+            | Self::JavaScript
+            | Self::JavaScriptReact
+            | Self::Kotlin
+            | Self::ObjectiveC
+            | Self::Rust
+            | Self::Scala
+            | Self::Swift
+            | Self::TypeScript
+            | Self::TypeScriptReact => "//",
+            Self::Erlang => "%",
+            Self::Lua => "--",
+            Self::Bash
+            | Self::Elixir
+            | Self::Html
+            | Self::Json
+            | Self::Markdown
+            | Self::Python
+            | Self::R
+            | Self::Ruby
+            | Self::Unknown => "#",
+        }
+    }
+
+    /// Beginnings of top level definitions, on which a multi-line completion can stop as it
+    /// moved past the block being completed.
+    pub(crate) fn stop_sequences(&self) -> &'static [&'static str] {
+        match self {
+            Self::Bash => &["\nfunction "],
+            Self::Elixir => &["\ndefmodule "],
+            Self::Go => &["\nfunc ", "\ntype "],
+            Self::JavaScript | Self::JavaScriptReact | Self::TypeScript | Self::TypeScriptReact => {
+                &["\nfunction ", "\nclass ", "\nexport "]
+            }
+            Self::Kotlin => &["\nfun ", "\nclass "],
+            Self::Lua => &["\nfunction ", "\nlocal function "],
+            Self::Python => &["\ndef ", "\nclass ", "\nif __name__"],
+            Self::Ruby => &["\ndef ", "\nclass ", "\nmodule "],
+            Self::Rust => &["\nfn ", "\nimpl ", "\npub fn "],
+            Self::Swift => &["\nfunc ", "\nclass ", "\nstruct "],
+            Self::C
+            | Self::Cpp
+            | Self::CSharp
+            | Self::Erlang
+            | Self::Html
+            | Self::Java
+            | Self::Json
+            | Self::Markdown
+            | Self::ObjectiveC
+            | Self::R
+            | Self::Scala
+            | Self::Unknown => &[],
+        }
+    }
+}
+
 impl From<&str> for LanguageId {
     fn from(value: &str) -> Self {
         match value {
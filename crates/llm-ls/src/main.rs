@@ -1,20 +1,31 @@
 use clap::Parser;
+use custom_types::inline_completion::{
+    InlineCompletionItem, InlineCompletionList, InlineCompletionParams,
+};
 use custom_types::llm_ls::{
-    AcceptCompletionParams, Backend, Completion, FimParams, GetCompletionsParams,
-    GetCompletionsResult, Ide, RejectCompletionParams, TokenizerConfig,
+    AcceptCompletionParams, Completion, CompletionCacheParams, CompletionType,
+    GetCompletionsParams, GetCompletionsProgressParams, GetCompletionsResult, Ide, LlmLsConfig,
+    LlmLsConfigOverrides, RejectCompletionParams, SkipReason, SuppressParams, TokenizerConfig,
+    WorkspaceIndexParams,
 };
+use custom_types::request::{GetCompletionsProgress, InlineCompletion};
+use futures_util::future::join_all;
+use futures_util::StreamExt;
 use ropey::Rope;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt::Display;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::time::{Duration, Instant, SystemTime};
 use tokenizers::Tokenizer;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
-use tower_lsp::jsonrpc::Result as LspResult;
+use tokio_util::sync::CancellationToken;
+use tower_lsp::jsonrpc::{Error as LspError, Result as LspResult};
+use tower_lsp::lsp_types::request::Request as _;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 use tracing::{debug, error, info, info_span, warn, Instrument};
@@ -22,14 +33,32 @@ use tracing_appender::rolling;
 use tracing_subscriber::EnvFilter;
 use uuid::Uuid;
 
-use crate::backend::{build_body, build_headers, parse_generations};
+use crate::backend::{
+    build_headers, BackendRegistry, CompletionBackend, CompletionRequest, StreamChunk,
+};
+use crate::cache::{config_key, prompt_key, CompletionCache};
+use crate::confidence::{confidence, filter_low_confidence};
+use crate::config::{ProjectConfig, ProjectConfigs, PROJECT_CONFIG_FILE_NAME};
+use crate::context::{format_snippet, gather_snippets, Snippet};
 use crate::document::Document;
 use crate::error::{internal_error, Error, Result};
+use crate::index::{read_update, WorkspaceIndex};
+use crate::language_id::LanguageId;
+use crate::postprocess::{cursor_position, overlap_range, trim_multi_line};
+use crate::prompt::{build_prompt, count_tokens, Prompt, PromptContext};
 
 mod backend;
+mod cache;
+mod completion_rules;
+mod confidence;
+mod config;
+mod context;
 mod document;
 mod error;
+mod index;
 mod language_id;
+mod postprocess;
+mod prompt;
 
 const MAX_WARNING_REPEAT: Duration = Duration::from_secs(3_600);
 pub const NAME: &str = "llm-ls";
@@ -45,29 +74,56 @@ fn get_position_idx(rope: &Rope, row: usize, col: usize) -> Result<usize> {
         ))
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
-enum CompletionType {
-    Empty,
-    SingleLine,
-    MultiLine,
-}
-
-impl Display for CompletionType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            CompletionType::Empty => write!(f, "empty"),
-            CompletionType::SingleLine => write!(f, "single_line"),
-            CompletionType::MultiLine => write!(f, "multi_line"),
+/// Stop sequences sent to the backend: the configured ones, followed by the end of the line for
+/// single line completions or the beginning of the next top level definition for multi-line ones.
+/// The configured ones come first so that they are kept by backends capping the number of stop
+/// sequences, the derived ones only save generating text that post processing cuts anyway.
+fn stop_sequences(
+    completion_type: &CompletionType,
+    language_id: LanguageId,
+    configured: Vec<String>,
+) -> Vec<String> {
+    let derived: &[&str] = match completion_type {
+        CompletionType::SingleLine => &["\n"],
+        CompletionType::MultiLine => language_id.stop_sequences(),
+    };
+    let mut stop_sequences = configured;
+    for &sequence in derived {
+        if !stop_sequences
+            .iter()
+            .any(|configured| configured == sequence)
+        {
+            stop_sequences.push(sequence.to_owned());
         }
     }
+    stop_sequences
 }
 
-fn should_complete(document: &Document, position: Position) -> Result<CompletionType> {
+fn should_complete(
+    document: &Document,
+    position: Position,
+    suppress: &SuppressParams,
+    position_encoding: document::PositionEncodingKind,
+) -> Result<std::result::Result<CompletionType, SkipReason>> {
     let row = position.line as usize;
     let column = position.character as usize;
     if document.text.len_chars() == 0 {
         warn!("Document is empty");
-        return Ok(CompletionType::Empty);
+        return Ok(Err(SkipReason::EmptyDocument));
+    }
+    // XXX: We treat the end of a document as a newline
+    let next_char = cursor_position(&document.text, position, position_encoding)
+        .and_then(|(char_idx, _)| document.text.get_char(char_idx))
+        .unwrap_or('\n');
+    if let Some(completion_type) =
+        completion_rules::completion_type(document, position, suppress, position_encoding)
+    {
+        // the rules match on the surrounding syntax, which says nothing about the cursor being
+        // in the middle of an identifier, e.g. `foo(ab|c)`
+        if completion_type.is_ok() && (next_char.is_alphanumeric() || next_char == '_') {
+            return Ok(Err(SkipReason::MidWord));
+        }
+        return Ok(completion_type);
     }
     if let Some(tree) = &document.tree {
         let current_node = tree.root_node().descendant_for_point_range(
@@ -79,7 +135,7 @@ fn should_complete(document: &Document, position: Position) -> Result<Completion
         );
         if let Some(node) = current_node {
             if node == tree.root_node() {
-                return Ok(CompletionType::MultiLine);
+                return Ok(Ok(CompletionType::MultiLine));
             }
             let start = node.start_position();
             let end = node.end_position();
@@ -100,30 +156,103 @@ fn should_complete(document: &Document, position: Position) -> Result<Completion
                 end_offset -= 1;
             }
             if start_offset >= end_offset {
-                return Ok(CompletionType::SingleLine);
+                return Ok(Ok(CompletionType::SingleLine));
             }
             let slice = document
                 .text
                 .get_slice(start_offset..end_offset)
                 .ok_or(Error::OutOfBoundSlice(start_offset, end_offset))?;
             if slice.to_string().trim().is_empty() {
-                return Ok(CompletionType::MultiLine);
+                return Ok(Ok(CompletionType::MultiLine));
             }
         }
     }
-    let start_idx = document.text.try_line_to_char(row)?;
-    // XXX: We treat the end of a document as a newline
-    let next_char = document.text.get_char(start_idx + column).unwrap_or('\n');
     if next_char.is_whitespace() {
-        Ok(CompletionType::SingleLine)
+        Ok(Ok(CompletionType::SingleLine))
     } else {
-        Ok(CompletionType::Empty)
+        Ok(Err(SkipReason::MidWord))
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Token {
+    text: String,
+    logprob: f32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Generation {
     generated_text: String,
+    /// Generated tokens with their log probability, when returned by the backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tokens: Option<Vec<Token>>,
+}
+
+impl Generation {
+    fn mean_logprob(&self) -> Option<f32> {
+        let tokens = self.tokens.as_ref().filter(|tokens| !tokens.is_empty())?;
+        Some(tokens.iter().map(|token| token.logprob).sum::<f32>() / tokens.len() as f32)
+    }
+}
+
+/// Sorts the generations from most to least likely, the ones without log probabilities last,
+/// keeping the order of the backend for ties.
+fn rank_generations(generations: &mut [Generation]) {
+    generations.sort_by(|a, b| match (a.mean_logprob(), b.mean_logprob()) {
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+}
+
+type InFlightRequests = Arc<Mutex<HashMap<String, (Uuid, CancellationToken)>>>;
+
+/// Completion request currently running for a document.
+///
+/// Registering a request cancels the one previously running for the same document, the entry
+/// is cleaned up when the request completes or is dropped, e.g. on `$/cancelRequest`.
+struct InFlightRequest {
+    requests: InFlightRequests,
+    uri: String,
+    request_id: Uuid,
+    cancellation_token: CancellationToken,
+}
+
+impl InFlightRequest {
+    fn register(requests: InFlightRequests, uri: String, request_id: Uuid) -> Self {
+        let cancellation_token = CancellationToken::new();
+        let previous = requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(uri.clone(), (request_id, cancellation_token.clone()));
+        if let Some((previous_request_id, previous_cancellation_token)) = previous {
+            debug!(%previous_request_id, "cancelling superseded completion request");
+            previous_cancellation_token.cancel();
+        }
+        Self {
+            requests,
+            uri,
+            request_id,
+            cancellation_token,
+        }
+    }
+
+    async fn cancelled(&self) {
+        self.cancellation_token.cancelled().await
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        let mut requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+        if requests
+            .get(&self.uri)
+            .is_some_and(|(request_id, _)| *request_id == self.request_id)
+        {
+            requests.remove(&self.uri);
+        }
+    }
 }
 
 struct LlmService {
@@ -131,6 +260,15 @@ struct LlmService {
     client: Client,
     document_map: Arc<RwLock<HashMap<String, Document>>>,
     http_client: reqwest::Client,
+    in_flight_requests: InFlightRequests,
+    completion_cache: Arc<Mutex<CompletionCache>>,
+    backends: Arc<BackendRegistry>,
+    /// Configuration sent through `initializationOptions` or `workspace/didChangeConfiguration`
+    client_config: Arc<RwLock<LlmLsConfigOverrides>>,
+    project_configs: Arc<RwLock<ProjectConfigs>>,
+    workspace_indexes: Arc<RwLock<Vec<WorkspaceIndex>>>,
+    /// Parameters the workspace folders are indexed with, once indexing started
+    index_params: Arc<RwLock<Option<WorkspaceIndexParams>>>,
     unsafe_http_client: reqwest::Client,
     workspace_folders: Arc<RwLock<Option<Vec<WorkspaceFolder>>>>,
     tokenizer_map: Arc<RwLock<HashMap<String, Arc<Tokenizer>>>>,
@@ -138,119 +276,189 @@ struct LlmService {
     position_encoding: Arc<RwLock<document::PositionEncodingKind>>,
 }
 
-fn build_prompt(
+/// Parses llm-ls' configuration from the client provided settings, which may be nested under an
+/// `llm-ls` section.
+fn parse_client_config(settings: Value) -> Result<LlmLsConfigOverrides> {
+    let settings = match settings {
+        Value::Null => return Ok(LlmLsConfigOverrides::default()),
+        Value::Object(mut settings) => match settings.remove(NAME) {
+            Some(section @ Value::Object(_)) => section,
+            Some(section) => {
+                settings.insert(NAME.to_owned(), section);
+                Value::Object(settings)
+            }
+            None => Value::Object(settings),
+        },
+        settings => settings,
+    };
+    Ok(serde_json::from_value(settings)?)
+}
+
+/// Path of a document relative to the workspace folder containing it, if any.
+fn display_path(uri: &str, workspace_roots: &[PathBuf]) -> String {
+    match Url::parse(uri).ok().and_then(|uri| uri.to_file_path().ok()) {
+        Some(path) => workspace_roots
+            .iter()
+            .find_map(|root| path.strip_prefix(root).ok())
+            .unwrap_or(&path)
+            .display()
+            .to_string(),
+        None => uri.to_owned(),
+    }
+}
+
+/// Name of the workspace folder containing a document, if any.
+fn repo_name(uri: &str, workspace_roots: &[PathBuf]) -> String {
+    Url::parse(uri)
+        .ok()
+        .and_then(|uri| uri.to_file_path().ok())
+        .and_then(|path| workspace_roots.iter().find(|root| path.starts_with(root)))
+        .and_then(|root| root.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Builds the context prepended to the prompt from the other open documents and the workspace
+/// index, within its share of the context window, along with the snippets it is made of.
+fn build_cross_file_context(
     pos: Position,
-    text: &Rope,
-    fim: &FimParams,
-    tokenizer: Option<Arc<Tokenizer>>,
-    context_window: usize,
-) -> Result<String> {
+    uri: &str,
+    document_map: &HashMap<String, Document>,
+    workspace_indexes: &[WorkspaceIndex],
+    workspace_roots: &[PathBuf],
+    config: &LlmLsConfig,
+    tokenizer: Option<&Tokenizer>,
+) -> Result<(String, Vec<Snippet>)> {
     let t = Instant::now();
-    if fim.enabled {
-        let mut remaining_token_count = context_window - 3; // account for FIM tokens
-        let mut before_iter = text.lines_at(pos.line as usize + 1).reversed();
-        let mut after_iter = text.lines_at(pos.line as usize);
-        let mut before_line = before_iter.next();
-        if let Some(line) = before_line {
-            let col = (pos.character as usize).clamp(0, line.len_chars());
-            before_line = Some(line.slice(0..col));
-        }
-        let mut after_line = after_iter.next();
-        if let Some(line) = after_line {
-            let col = (pos.character as usize).clamp(0, line.len_chars());
-            after_line = Some(line.slice(col..));
-        }
-        let mut before = vec![];
-        let mut after = String::new();
-        while before_line.is_some() || after_line.is_some() {
-            if let Some(before_line) = before_line {
-                let before_line = before_line.to_string();
-                let tokens = if let Some(tokenizer) = tokenizer.clone() {
-                    tokenizer.encode(before_line.clone(), false)?.len()
-                } else {
-                    before_line.len()
-                };
-                if tokens > remaining_token_count {
-                    break;
-                }
-                remaining_token_count -= tokens;
-                before.push(before_line);
-            }
-            if let Some(after_line) = after_line {
-                let after_line = after_line.to_string();
-                let tokens = if let Some(tokenizer) = tokenizer.clone() {
-                    tokenizer.encode(after_line.clone(), false)?.len()
-                } else {
-                    after_line.len()
-                };
-                if tokens > remaining_token_count {
-                    break;
-                }
-                remaining_token_count -= tokens;
-                after.push_str(&after_line);
-            }
-            before_line = before_iter.next();
-            after_line = after_iter.next();
-        }
-        let prompt = format!(
-            "{}{}{}{}{}",
-            fim.prefix,
-            before.into_iter().rev().collect::<Vec<_>>().join(""),
-            fim.suffix,
-            after,
-            fim.middle
+    let params = &config.cross_file_context;
+    let Some(document) = document_map.get(uri) else {
+        return Ok((String::new(), vec![]));
+    };
+    let text = &document.text;
+    let line = (pos.line as usize).min(text.len_lines().saturating_sub(1));
+    let query_start = text.line_to_char((line + 1).saturating_sub(params.window_size));
+    let query_end =
+        text.line_to_char(line) + (pos.character as usize).min(text.line(line).len_chars());
+    let query = text.slice(query_start..query_end).to_string();
+
+    let mut snippets = vec![];
+    if params.enabled {
+        let documents = document_map
+            .iter()
+            .filter(|(document_uri, _)| document_uri.as_str() != uri)
+            .map(|(document_uri, document)| {
+                (display_path(document_uri, workspace_roots), document)
+            });
+        snippets.extend(gather_snippets(&query, documents, params));
+    }
+    if config.workspace_index.enabled {
+        // the other open documents are already taken into account above when enabled
+        let excluded = document_map
+            .keys()
+            .filter(|document_uri| params.enabled || document_uri.as_str() == uri)
+            .filter_map(|uri| Url::parse(uri).ok()?.to_file_path().ok())
+            .collect::<HashSet<_>>();
+        let mut index_snippets = workspace_indexes
+            .iter()
+            .flat_map(|index| index.search(&query, config.workspace_index.top_k, &excluded))
+            .collect::<Vec<_>>();
+        index_snippets.sort_by(|a, b| b.score.total_cmp(&a.score));
+        index_snippets.truncate(config.workspace_index.top_k);
+        snippets.extend(index_snippets);
+    }
+
+    let max_tokens = params.max_tokens.min(config.context_window / 2);
+    let mut token_count = 0;
+    let mut context = String::new();
+    let mut kept = vec![];
+    for snippet in snippets {
+        let formatted = format_snippet(
+            &snippet,
+            document.language_id.line_comment(),
+            config.fim.file_separator.as_deref(),
         );
-        let time = t.elapsed().as_millis();
-        info!(prompt, build_prompt_ms = time, "built prompt in {time} ms");
-        Ok(prompt)
-    } else {
-        let mut remaining_token_count = context_window;
-        let mut before = vec![];
-        let mut first = true;
-        for mut line in text.lines_at(pos.line as usize + 1).reversed() {
-            if first {
-                let col = (pos.character as usize).clamp(0, line.len_chars());
-                line = line.slice(0..col);
-                first = false;
-            }
-            let line = line.to_string();
-            let tokens = if let Some(tokenizer) = tokenizer.clone() {
-                tokenizer.encode(line.clone(), false)?.len()
-            } else {
-                line.len()
-            };
-            if tokens > remaining_token_count {
-                break;
+        let tokens = count_tokens(tokenizer, &formatted)?;
+        if token_count + tokens > max_tokens {
+            continue;
+        }
+        token_count += tokens;
+        context.push_str(&formatted);
+        kept.push(snippet);
+    }
+    let time = t.elapsed().as_millis();
+    info!(
+        context,
+        build_context_ms = time,
+        "built cross-file context in {time} ms"
+    );
+    Ok((context, kept))
+}
+
+/// Number of candidates to ask each request for, in as few requests as the backend allows.
+fn candidate_batches(num_candidates: usize, max_num_candidates: usize) -> Vec<usize> {
+    let max_num_candidates = max_num_candidates.max(1);
+    (0..num_candidates)
+        .step_by(max_num_candidates)
+        .map(|start| max_num_candidates.min(num_candidates - start))
+        .collect()
+}
+
+/// Requests `config.num_candidates` generations, in as few concurrent requests as the backend
+/// allows, and ranks them. Failed requests are left out, unless they all failed.
+async fn request_completions(
+    http_client: &reqwest::Client,
+    backend: &dyn CompletionBackend,
+    prompt: &Prompt,
+    config: &LlmLsConfig,
+    ide: Ide,
+) -> Result<Vec<Generation>> {
+    let batches = candidate_batches(config.num_candidates.max(1), backend.max_num_candidates());
+    let results = join_all(batches.into_iter().map(|num_candidates| {
+        request_completion(http_client, backend, prompt, config, ide, num_candidates)
+    }))
+    .await;
+    let mut generations = vec![];
+    let mut first_err = None;
+    for result in results {
+        match result {
+            Ok(candidates) => generations.extend(candidates),
+            Err(err) => {
+                warn!("failed to request completion candidates: {err}");
+                first_err.get_or_insert(err);
             }
-            remaining_token_count -= tokens;
-            before.push(line);
         }
-        let prompt = before.into_iter().rev().collect::<Vec<_>>().join("");
-        let time = t.elapsed().as_millis();
-        info!(prompt, build_prompt_ms = time, "built prompt in {time} ms");
-        Ok(prompt)
     }
+    if let Some(err) = first_err.filter(|_| generations.is_empty()) {
+        return Err(err);
+    }
+    rank_generations(&mut generations);
+    Ok(generations)
 }
 
 async fn request_completion(
     http_client: &reqwest::Client,
-    prompt: String,
-    params: &GetCompletionsParams,
+    backend: &dyn CompletionBackend,
+    prompt: &Prompt,
+    config: &LlmLsConfig,
+    ide: Ide,
+    num_candidates: usize,
 ) -> Result<Vec<Generation>> {
     let t = Instant::now();
 
-    let json = build_body(
-        &params.backend,
-        params.model.clone(),
-        prompt,
-        params.request_body.clone(),
-    );
-    let headers = build_headers(&params.backend, params.api_token.as_ref(), params.ide)?;
-    let url = build_url(
-        params.backend.clone(),
-        &params.model,
-        params.disable_url_path_completion,
-    );
+    let json = backend.build_body(CompletionRequest {
+        model: &config.model,
+        prompt: prompt.text.clone(),
+        suffix: prompt.suffix.clone(),
+        snippets: &prompt.snippets,
+        request_body: config.request_body.clone(),
+        stop_tokens: &config.stop_tokens,
+        num_candidates,
+        sample: config.num_candidates > 1,
+        stream: false,
+        chat: &config.chat,
+    });
+    let headers = backend.build_headers(config.api_token.as_ref(), ide)?;
+    let url = build_url(backend, config, false);
     info!(?headers, url, "sending request to backend");
     debug!(?headers, body = ?json, url, "sending request to backend");
     let res = http_client
@@ -259,9 +467,12 @@ async fn request_completion(
         .headers(headers)
         .send()
         .await?;
+    if let Some(err) = backend.error_for_status(res.status()) {
+        return Err(err);
+    }
 
-    let model = &params.model;
-    let generations = parse_generations(&params.backend, res.text().await?.as_str())?;
+    let model = &config.model;
+    let generations = backend.parse_generations(res.text().await?.as_str())?;
     let time = t.elapsed().as_millis();
     info!(
         model,
@@ -272,34 +483,253 @@ async fn request_completion(
     Ok(generations)
 }
 
+fn clear_tokens(mut text: String, tokens_to_clear: &[String]) -> String {
+    for token in tokens_to_clear {
+        text = text.replace(token, "")
+    }
+    text
+}
+
+/// Byte range of `text` from which the first `len` bytes of `clear_tokens(text, tokens_to_clear)`
+/// come.
+fn cleared_span(text: &str, tokens_to_clear: &[String], len: usize) -> std::ops::Range<usize> {
+    let (mut start, mut idx, mut kept) = (None, 0, 0);
+    while kept < len {
+        let rest = &text[idx..];
+        if let Some(token) = tokens_to_clear
+            .iter()
+            .find(|token| !token.is_empty() && rest.starts_with(token.as_str()))
+        {
+            idx += token.len();
+            continue;
+        }
+        let Some(c) = rest.chars().next() else {
+            break;
+        };
+        start.get_or_insert(idx);
+        idx += c.len_utf8();
+        kept += c.len_utf8();
+    }
+    start.unwrap_or(idx)..idx
+}
+
+/// Length of the end of `text` that may be the beginning of one of `tokens_to_clear`, held back
+/// until the next chunks tell whether it has to be cleared.
+fn pending_token_len(text: &str, tokens_to_clear: &[String]) -> usize {
+    tokens_to_clear
+        .iter()
+        .flat_map(|token| {
+            token
+                .char_indices()
+                .skip(1)
+                .map(|(idx, _)| &token[..idx])
+                .filter(|prefix| text.ends_with(prefix))
+                .map(str::len)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Sends partial results of the `llm-ls/getCompletions` request identified by `token`.
+struct PartialResultSender<'a> {
+    client: &'a Client,
+    request_id: Uuid,
+    token: ProgressToken,
+    sent: AtomicBool,
+}
+
+impl PartialResultSender<'_> {
+    /// Whether partial results were sent, in which case the final result must be empty.
+    fn sent(&self) -> bool {
+        self.sent.load(Ordering::SeqCst)
+    }
+
+    /// Sends the completions as they currently are, replacing the ones sent before. The
+    /// completion type is only set once they are post-processed.
+    async fn send(&self, completions: Vec<Completion>, completion_type: Option<CompletionType>) {
+        self.sent.store(true, Ordering::SeqCst);
+        self.client
+            .send_notification::<GetCompletionsProgress>(GetCompletionsProgressParams {
+                token: self.token.clone(),
+                value: GetCompletionsResult {
+                    request_id: self.request_id,
+                    completions,
+                    skip_reason: None,
+                    completion_type,
+                    prompt_tokens: None,
+                },
+            })
+            .await;
+    }
+}
+
+/// Streams the generation from the backend, forwarding the text generated so far to the client
+/// as it grows. The raw generation is returned to be post-processed once the stream ends.
+async fn stream_completion(
+    http_client: &reqwest::Client,
+    backend: &dyn CompletionBackend,
+    prompt: &Prompt,
+    config: &LlmLsConfig,
+    ide: Ide,
+    completion_type: &CompletionType,
+    partial_results: &PartialResultSender<'_>,
+) -> Result<Vec<Generation>> {
+    let t = Instant::now();
+
+    let json = backend.build_body(CompletionRequest {
+        model: &config.model,
+        prompt: prompt.text.clone(),
+        suffix: prompt.suffix.clone(),
+        snippets: &prompt.snippets,
+        request_body: config.request_body.clone(),
+        stop_tokens: &config.stop_tokens,
+        num_candidates: 1,
+        sample: false,
+        stream: true,
+        chat: &config.chat,
+    });
+    let headers = backend.build_headers(config.api_token.as_ref(), ide)?;
+    let url = build_url(backend, config, true);
+    info!(?headers, url, "sending streaming request to backend");
+    debug!(?headers, body = ?json, url, "sending streaming request to backend");
+    let res = http_client
+        .post(url)
+        .json(&json)
+        .headers(headers)
+        .send()
+        .await?;
+    if let Some(err) = backend.error_for_status(res.status()) {
+        return Err(err);
+    }
+    if !res.status().is_success() {
+        // errors are never streamed, parse them as a regular response
+        return backend.parse_generations(res.text().await?.as_str());
+    }
+
+    let mut stream = res.bytes_stream();
+    let mut buffer = vec![];
+    let mut generated_text = String::new();
+    let mut sent_text = String::new();
+    let mut done = false;
+    while !done {
+        let line = match buffer.iter().position(|b| *b == b'\n') {
+            Some(idx) => buffer.drain(..=idx).collect::<Vec<_>>(),
+            None => match stream.next().await {
+                Some(bytes) => {
+                    buffer.extend_from_slice(&bytes?);
+                    continue;
+                }
+                None => {
+                    done = true;
+                    std::mem::take(&mut buffer)
+                }
+            },
+        };
+        let line = String::from_utf8_lossy(&line);
+        let Some(data) = backend.stream_line_payload(&line) else {
+            continue;
+        };
+        match backend.parse_stream_chunk(data)? {
+            StreamChunk::Text(text) => generated_text.push_str(&text),
+            StreamChunk::Done => break,
+        };
+        // tokens are cleared from the whole text as they may be split across chunks
+        let mut text = clear_tokens(generated_text.clone(), &config.tokens_to_clear);
+        if *completion_type == CompletionType::SingleLine {
+            if let Some((line, _)) = text.split_once('\n') {
+                text = line.to_owned();
+                done = true;
+            }
+        }
+        if !done {
+            text.truncate(text.len() - pending_token_len(&text, &config.tokens_to_clear));
+        }
+        if text.is_empty() || text == sent_text {
+            continue;
+        }
+        sent_text.clone_from(&text);
+        partial_results
+            .send(
+                vec![Completion {
+                    generated_text: text,
+                    range: None,
+                    confidence: None,
+                }],
+                None,
+            )
+            .await;
+    }
+
+    let model = &config.model;
+    let time = t.elapsed().as_millis();
+    info!(
+        model,
+        compute_generations_ms = time,
+        generated_text,
+        "{model} streamed generation in {time} ms"
+    );
+    Ok(vec![Generation {
+        generated_text,
+        ..Default::default()
+    }])
+}
+
 fn format_generations(
     generations: Vec<Generation>,
+    min_confidence: Option<f32>,
     tokens_to_clear: &[String],
     completion_type: CompletionType,
+    document: &Document,
+    position: Position,
+    position_encoding: document::PositionEncodingKind,
 ) -> Vec<Completion> {
     generations
         .into_iter()
+        .filter_map(|g| match min_confidence {
+            Some(min_confidence) => filter_low_confidence(g, min_confidence),
+            None => Some(g),
+        })
         .map(|g| {
-            let mut generated_text = g.generated_text;
-            for token in tokens_to_clear {
-                generated_text = generated_text.replace(token, "")
-            }
-            match completion_type {
-                CompletionType::Empty => {
-                    warn!("completion type should not be empty when post processing completions");
-                    Completion { generated_text }
+            let generated_text = clear_tokens(g.generated_text.clone(), tokens_to_clear);
+            let generated_text = match completion_type {
+                CompletionType::SingleLine => generated_text
+                    .split_once('\n')
+                    .unwrap_or((&generated_text, ""))
+                    .0
+                    .to_owned(),
+                CompletionType::MultiLine => {
+                    trim_multi_line(document, position, &generated_text, position_encoding)
                 }
-                CompletionType::SingleLine => Completion {
-                    generated_text: generated_text
-                        .split_once('\n')
-                        .unwrap_or((&generated_text, ""))
-                        .0
-                        .to_owned(),
-                },
-                CompletionType::MultiLine => Completion { generated_text },
+            };
+            let range = overlap_range(
+                document,
+                position,
+                &generated_text,
+                completion_type == CompletionType::SingleLine,
+                position_encoding,
+            );
+            // post processing clears tokens anywhere in the generated text and then only cuts its
+            // end, the completion comes from the span of the raw text it was cleared from
+            let confidence = confidence(
+                &g,
+                cleared_span(&g.generated_text, tokens_to_clear, generated_text.len()),
+            );
+            Completion {
+                generated_text,
+                range,
+                confidence,
             }
         })
-        .collect()
+        .fold(vec![], |mut completions: Vec<Completion>, completion| {
+            // candidates often only differ by what post processing removed
+            if !completions
+                .iter()
+                .any(|c| c.generated_text == completion.generated_text)
+            {
+                completions.push(completion);
+            }
+            completions
+        })
 }
 
 async fn download_tokenizer_file(
@@ -313,10 +743,11 @@ async fn download_tokenizer_file(
         return Ok(());
     }
     tokio::fs::create_dir_all(to.as_ref().parent().ok_or(Error::InvalidTokenizerPath)?).await?;
-    let headers = build_headers(&Backend::default(), api_token, ide)?;
+    let headers = build_headers(api_token, ide)?;
     let mut file = tokio::fs::OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(true)
         .open(to)
         .await?;
     let http_client = http_client.clone();
@@ -421,88 +852,180 @@ async fn get_tokenizer(
     }
 }
 
-// TODO: add configuration parameter to disable path auto-complete?
-fn build_url(backend: Backend, model: &str, disable_url_path_completion: bool) -> String {
-    if disable_url_path_completion {
-        return backend.url();
+/// URL of the backend's route, unless path completion is disabled.
+fn build_url(backend: &dyn CompletionBackend, config: &LlmLsConfig, stream: bool) -> String {
+    let url = config.backend.clone().url();
+    if config.disable_url_path_completion {
+        return url;
     }
+    backend.build_url(url, &config.model, stream)
+}
 
-    match backend {
-        Backend::HuggingFace { url } => format!("{url}/models/{model}"),
-        Backend::LlamaCpp { mut url } => {
-            if url.ends_with("/v1/completions") {
-                url
-            } else if url.ends_with("/v1/") {
-                url.push_str("completions");
-                url
-            } else if url.ends_with("/v1") {
-                url.push_str("/completions");
-                url
-            } else if url.ends_with('/') {
-                url.push_str("v1/completions");
-                url
-            } else {
-                url.push_str("/v1/completions");
-                url
-            }
+impl LlmService {
+    /// Configuration before applying the project and request overrides.
+    async fn base_config(&self) -> LlmLsConfig {
+        let mut config = LlmLsConfig::default();
+        config.apply(self.client_config.read().await.clone());
+        config
+    }
+
+    /// Indexes the workspace folders in the background, once, the first time it is enabled by
+    /// the client or a project configuration, and watches their files to keep the index up to
+    /// date.
+    async fn start_indexing(&self, params: &WorkspaceIndexParams) {
+        if !params.enabled {
+            return;
         }
-        Backend::Ollama { mut url } => {
-            if url.ends_with("/api/generate") {
-                url
-            } else if url.ends_with("/api/") {
-                url.push_str("generate");
-                url
-            } else if url.ends_with("/api") {
-                url.push_str("/generate");
-                url
-            } else if url.ends_with('/') {
-                url.push_str("api/generate");
-                url
-            } else {
-                url.push_str("/api/generate");
-                url
+        {
+            let mut index_params = self.index_params.write().await;
+            if index_params.is_some() {
+                return;
             }
+            *index_params = Some(params.clone());
         }
-        Backend::OpenAi { mut url } => {
-            if url.ends_with("/v1/completions") {
-                url
-            } else if url.ends_with("/v1/") {
-                url.push_str("completions");
-                url
-            } else if url.ends_with("/v1") {
-                url.push_str("/completions");
-                url
-            } else if url.ends_with('/') {
-                url.push_str("v1/completions");
-                url
-            } else {
-                url.push_str("/v1/completions");
-                url
+        let params = params.clone();
+        let watchers = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: GlobPattern::String("**/*".to_owned()),
+                kind: None,
+            }],
+        };
+        let registration = Registration {
+            id: "workspaceIndex".to_owned(),
+            method: "workspace/didChangeWatchedFiles".to_owned(),
+            register_options: serde_json::to_value(watchers).ok(),
+        };
+        // not awaited as indexing may start while answering a completion request
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(err) = client.register_capability(vec![registration]).await {
+                debug!("client does not support watching files: {err}");
             }
-        }
-        Backend::Tgi { mut url } => {
-            if url.ends_with("/generate") {
-                url
-            } else if url.ends_with('/') {
-                url.push_str("generate");
-                url
-            } else {
-                url.push_str("/generate");
-                url
+        });
+        let roots = self.workspace_roots().await;
+        let cache_dir = self.cache_dir.clone();
+        let workspace_indexes = self.workspace_indexes.clone();
+        tokio::spawn(async move {
+            for root in roots {
+                let cache_dir = cache_dir.clone();
+                let params = params.clone();
+                let index = tokio::task::spawn_blocking(move || {
+                    let mut index = WorkspaceIndex::load(&cache_dir, root);
+                    index.refresh(&params);
+                    if let Err(err) = index.save(&cache_dir) {
+                        error!("error saving index of {}: {err}", index.root().display());
+                    }
+                    index
+                })
+                .await;
+                match index {
+                    Ok(index) => workspace_indexes.write().await.push(index),
+                    Err(err) => error!("error indexing workspace folder: {err}"),
+                }
             }
+        });
+    }
+
+    async fn update_index(&self, path: PathBuf) {
+        let Some(params) = self.index_params.read().await.clone() else {
+            return;
+        };
+        let located = self
+            .workspace_indexes
+            .read()
+            .await
+            .iter()
+            .find_map(|index| {
+                let (relative_path, indexed) = index.locate(&path)?;
+                Some((index.root().to_path_buf(), relative_path, indexed))
+            });
+        let Some((root, relative_path, indexed)) = located else {
+            return;
+        };
+        // the file is read and chunked without holding the lock, only taken to apply the change
+        let update = {
+            let root = root.clone();
+            tokio::task::spawn_blocking(move || read_update(&root, relative_path, indexed, &params))
+                .await
+        };
+        match update {
+            Ok(update) => {
+                if let Some(index) = self
+                    .workspace_indexes
+                    .write()
+                    .await
+                    .iter_mut()
+                    .find(|index| index.root() == root)
+                {
+                    index.apply(update);
+                    debug!("updated index for {}", path.display());
+                }
+            }
+            Err(err) => error!("error updating index for {}: {err}", path.display()),
         }
     }
-}
 
-impl LlmService {
+    fn completion_cache(&self, params: &CompletionCacheParams) -> MutexGuard<'_, CompletionCache> {
+        let mut cache = self
+            .completion_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache.resize(params.capacity);
+        cache
+    }
+
+    async fn workspace_roots(&self) -> Vec<PathBuf> {
+        self.workspace_folders
+            .read()
+            .await
+            .iter()
+            .flatten()
+            .filter_map(|folder| folder.uri.to_file_path().ok())
+            .collect()
+    }
+
+    async fn find_project_config(&self, uri: &Url) -> Option<(PathBuf, Arc<ProjectConfig>)> {
+        let path = uri.to_file_path().ok()?;
+        let workspace_roots = self.workspace_roots().await;
+        let config = ProjectConfigs::find(&self.project_configs, &path, &workspace_roots).await?;
+        Some((path, config))
+    }
+
     async fn get_completions(
         &self,
         params: GetCompletionsParams,
     ) -> LspResult<GetCompletionsResult> {
         let request_id = Uuid::new_v4();
         let span = info_span!("completion_request", %request_id);
+        let in_flight_request = InFlightRequest::register(
+            self.in_flight_requests.clone(),
+            params.text_document_position.text_document.uri.to_string(),
+            request_id,
+        );
+
+        let completions = async move {
+            let mut config = self.base_config().await;
+            config.apply(params.config);
+            let project_config = self
+                .find_project_config(&params.text_document_position.text_document.uri)
+                .await;
+            if let Some((path, project_config)) = project_config {
+                if project_config.is_excluded(&path) {
+                    info!("completions are disabled for {}", path.display());
+                    return Ok(GetCompletionsResult::skipped(
+                        request_id,
+                        SkipReason::Excluded,
+                    ));
+                }
+                // applied last as clients send their whole configuration with every request,
+                // which would leave nothing for the project configuration to override
+                config.apply(project_config.overrides.clone());
+            }
+            self.start_indexing(&config.workspace_index).await;
+            if let Some(debounce_ms) = config.debounce_ms {
+                tokio::time::sleep(Duration::from_millis(debounce_ms)).await;
+            }
 
-        async move {
             let document_map = self.document_map.read().await;
 
             let document =
@@ -510,10 +1033,10 @@ impl LlmService {
                     Some(doc) => doc,
                     None => {
                         debug!("failed to find document");
-                        return Ok(GetCompletionsResult {
+                        return Ok(GetCompletionsResult::skipped(
                             request_id,
-                            completions: vec![],
-                        });
+                            SkipReason::DocumentNotFound,
+                        ));
                     }
                 };
 
@@ -522,62 +1045,242 @@ impl LlmService {
                 cursor_line = ?params.text_document_position.position.line,
                 cursor_character = ?params.text_document_position.position.character,
                 language_id = %document.language_id,
-                model = params.model,
-                backend = ?params.backend,
+                model = config.model,
+                backend = ?config.backend,
                 ide = %params.ide,
-                request_body = serde_json::to_string(&params.request_body).map_err(internal_error)?,
-                disable_url_path_completion = params.disable_url_path_completion,
+                request_body = serde_json::to_string(&config.request_body).map_err(internal_error)?,
+                disable_url_path_completion = config.disable_url_path_completion,
                 "received completion request",
             );
-            if params.api_token.is_none() && params.backend.is_using_inference_api() {
+            if config.api_token.is_none() && config.backend.is_using_inference_api() {
                 let now = SystemTime::now();
                 let unauthenticated_warn_at = self.unauthenticated_warn_at.read().await;
-                if now.duration_since(*unauthenticated_warn_at).unwrap_or_default() > MAX_WARNING_REPEAT {
+                if now
+                    .duration_since(*unauthenticated_warn_at)
+                    .unwrap_or_default()
+                    > MAX_WARNING_REPEAT
+                {
                     drop(unauthenticated_warn_at);
                     self.client.show_message(MessageType::WARNING, "You are currently unauthenticated and will get rate limited. To reduce rate limiting, login with your API Token and consider subscribing to PRO: https://huggingface.co/pricing#pro").await;
                     let mut unauthenticated_warn_at = self.unauthenticated_warn_at.write().await;
                     *unauthenticated_warn_at = SystemTime::now();
                 }
             }
-            let completion_type = should_complete(document, params.text_document_position.position)?;
+            let position_encoding = *self.position_encoding.read().await;
+            let completion_type = match should_complete(
+                document,
+                params.text_document_position.position,
+                &config.suppress,
+                position_encoding,
+            )? {
+                Ok(completion_type) => completion_type,
+                Err(skip_reason) => {
+                    info!(?skip_reason, "skipping completion");
+                    return Ok(GetCompletionsResult::skipped(request_id, skip_reason));
+                }
+            };
             info!(%completion_type, "completion type: {completion_type:?}");
-            if completion_type == CompletionType::Empty {
-                return Ok(GetCompletionsResult { request_id, completions: vec![]});
+            config.stop_tokens =
+                stop_sequences(&completion_type, document.language_id, config.stop_tokens);
+
+            let position = params.text_document_position.position;
+            let config_key = config_key(&config);
+            let cursor = cursor_position(&document.text, position, position_encoding)
+                .map(|(char_idx, _)| char_idx);
+            let cache_ttl = Duration::from_millis(config.cache.ttl_ms);
+            let typed_ahead = cursor.filter(|_| config.cache.enabled).and_then(|cursor| {
+                self.completion_cache(&config.cache).get_typed_ahead(
+                    config_key,
+                    params.text_document_position.text_document.uri.as_str(),
+                    &document.text,
+                    cursor,
+                    cache_ttl,
+                )
+            });
+            if let Some(generations) = typed_ahead {
+                info!("completing the remainder of a cached generation");
+                return Ok(GetCompletionsResult {
+                    request_id,
+                    completions: format_generations(
+                        generations,
+                        config.min_confidence,
+                        &config.tokens_to_clear,
+                        completion_type,
+                        document,
+                        position,
+                        position_encoding,
+                    ),
+                    skip_reason: None,
+                    completion_type: Some(completion_type),
+                    prompt_tokens: None,
+                });
             }
 
+            let backend = self.backends.get(&config.backend)?;
+            if config.fim.template.is_none() {
+                config.fim.template = backend.default_fim_template().map(str::to_owned);
+            }
             let tokenizer = get_tokenizer(
-                &params.model,
+                &config.model,
                 &mut *self.tokenizer_map.write().await,
-                params.tokenizer_config.as_ref(),
+                config.tokenizer_config.as_ref(),
                 &self.http_client,
                 &self.cache_dir,
                 params.ide,
             )
             .await?;
+            let uri = params.text_document_position.text_document.uri.as_str();
+            let workspace_roots = self.workspace_roots().await;
+            let (cross_file, snippets) =
+                if config.cross_file_context.enabled || config.workspace_index.enabled {
+                    build_cross_file_context(
+                        params.text_document_position.position,
+                        uri,
+                        &document_map,
+                        &self.workspace_indexes.read().await,
+                        &workspace_roots,
+                        &config,
+                        tokenizer.as_deref(),
+                    )?
+                } else {
+                    (String::new(), vec![])
+                };
+            let context = PromptContext {
+                cross_file,
+                snippets,
+                filename: display_path(uri, &workspace_roots),
+                repo: repo_name(uri, &workspace_roots),
+            };
             let prompt = build_prompt(
-                params.text_document_position.position,
-                &document.text,
-                &params.fim,
+                position,
+                document,
+                &config.fim,
+                &context,
                 tokenizer,
-                params.context_window,
+                config.context_window,
+                backend.prompt_layout(),
             )?;
+            let token_counts = prompt.token_counts.clone();
+            let prompt_key = prompt_key(config_key, &prompt);
+            let cached = if config.cache.enabled {
+                self.completion_cache(&config.cache)
+                    .get(prompt_key, cache_ttl)
+            } else {
+                None
+            };
 
-            let http_client = if params.tls_skip_verify_insecure {
+            let http_client = if config.tls_skip_verify_insecure {
                 info!("tls verification is disabled");
                 &self.unsafe_http_client
             } else {
                 &self.http_client
             };
-            let result = request_completion(
-                http_client,
-                prompt,
-                &params,
-            )
-            .await?;
+            // candidates are only streamed when there is a single one
+            let partial_results = params
+                .partial_result_params
+                .partial_result_token
+                .clone()
+                .filter(|_| config.num_candidates <= 1 && backend.supports_streaming())
+                .map(|token| PartialResultSender {
+                    client: &self.client,
+                    request_id,
+                    token,
+                    sent: AtomicBool::new(false),
+                });
+            let result = match (cached, &partial_results) {
+                (Some(generations), _) => {
+                    info!("found generations for this prompt in cache");
+                    Ok(generations)
+                }
+                (None, Some(partial_results)) => {
+                    stream_completion(
+                        http_client,
+                        backend,
+                        &prompt,
+                        &config,
+                        params.ide,
+                        &completion_type,
+                        partial_results,
+                    )
+                    .await
+                }
+                (None, None) => {
+                    request_completions(http_client, backend, &prompt, &config, params.ide).await
+                }
+            };
+            let result = match result {
+                Ok(generations) => generations,
+                Err(err) => {
+                    error!("error while requesting completions: {err}");
+                    let skip_reason = match err {
+                        Error::RateLimited => SkipReason::RateLimited,
+                        err => SkipReason::BackendError {
+                            message: err.to_string(),
+                        },
+                    };
+                    return Ok(GetCompletionsResult {
+                        request_id,
+                        completions: vec![],
+                        skip_reason: Some(skip_reason),
+                        completion_type: Some(completion_type),
+                        prompt_tokens: Some(token_counts),
+                    });
+                }
+            };
+            if let Some(cursor) = cursor.filter(|_| config.cache.enabled) {
+                self.completion_cache(&config.cache).insert(
+                    prompt_key,
+                    config_key,
+                    uri.to_owned(),
+                    document.text.clone(),
+                    cursor,
+                    result.clone(),
+                );
+            }
+
+            let completions = format_generations(
+                result,
+                config.min_confidence,
+                &config.tokens_to_clear,
+                completion_type,
+                document,
+                position,
+                position_encoding,
+            );
+            // the post-processed completions replace the streamed text, the final result must
+            // then be empty
+            if let Some(partial_results) = partial_results.filter(PartialResultSender::sent) {
+                partial_results
+                    .send(completions, Some(completion_type))
+                    .await;
+                return Ok(GetCompletionsResult {
+                    request_id,
+                    completions: vec![],
+                    skip_reason: None,
+                    completion_type: Some(completion_type),
+                    prompt_tokens: Some(token_counts),
+                });
+            }
+            Ok(GetCompletionsResult {
+                request_id,
+                completions,
+                skip_reason: None,
+                completion_type: Some(completion_type),
+                prompt_tokens: Some(token_counts),
+            })
+        };
 
-            let completions = format_generations(result, &params.tokens_to_clear, completion_type);
-            Ok(GetCompletionsResult { request_id, completions })
-        }.instrument(span).await
+        async move {
+            tokio::select! {
+                _ = in_flight_request.cancelled() => {
+                    info!("completion request superseded by a newer request");
+                    Err(LspError::request_cancelled())
+                }
+                result = completions => result,
+            }
+        }
+        .instrument(span)
+        .await
     }
 
     async fn accept_completion(&self, accepted: AcceptCompletionParams) -> LspResult<()> {
@@ -598,12 +1301,44 @@ impl LlmService {
         );
         Ok(())
     }
+
+    async fn inline_completion(
+        &self,
+        params: InlineCompletionParams,
+    ) -> LspResult<Option<InlineCompletionList>> {
+        let position = params.text_document_position.position;
+        let params = GetCompletionsParams {
+            text_document_position: params.text_document_position,
+            partial_result_params: PartialResultParams::default(),
+            ide: Ide::default(),
+            config: LlmLsConfigOverrides::default(),
+        };
+        let result = self.get_completions(params).await?;
+        Ok(Some(InlineCompletionList {
+            items: result
+                .completions
+                .into_iter()
+                .map(|completion| InlineCompletionItem {
+                    insert_text: completion.generated_text,
+                    filter_text: None,
+                    range: Some(
+                        completion
+                            .range
+                            .unwrap_or_else(|| Range::new(position, position)),
+                    ),
+                    command: None,
+                })
+                .collect(),
+        }))
+    }
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for LlmService {
     async fn initialize(&self, params: InitializeParams) -> LspResult<InitializeResult> {
         *self.workspace_folders.write().await = params.workspace_folders;
+        *self.client_config.write().await =
+            parse_client_config(params.initialization_options.unwrap_or_default())?;
         let position_encoding = params
             .capabilities
             .general
@@ -626,18 +1361,73 @@ impl LanguageServer for LlmService {
                     TextDocumentSyncKind::INCREMENTAL,
                 )),
                 position_encoding: Some(position_encoding.to_lsp_type()),
+                // `lsp_types` does not know about `inlineCompletionProvider` yet, which clients
+                // only read at the top level of the capabilities: it is registered dynamically
+                // once initialized
+                experimental: Some(serde_json::json!({ "inlineCompletionProvider": true })),
                 ..Default::default()
             },
         })
     }
 
     async fn initialized(&self, _: InitializedParams) {
+        let registration = Registration {
+            id: InlineCompletion::METHOD.to_owned(),
+            method: InlineCompletion::METHOD.to_owned(),
+            // a `null` document selector stands for the selector of the client
+            register_options: Some(serde_json::json!({ "documentSelector": null })),
+        };
+        if let Err(err) = self.client.register_capability(vec![registration]).await {
+            debug!("client does not support registering inline completions: {err}");
+        }
+        let watchers = DidChangeWatchedFilesRegistrationOptions {
+            watchers: vec![FileSystemWatcher {
+                glob_pattern: GlobPattern::String(format!("**/{PROJECT_CONFIG_FILE_NAME}")),
+                kind: None,
+            }],
+        };
+        let registration = Registration {
+            id: PROJECT_CONFIG_FILE_NAME.to_owned(),
+            method: "workspace/didChangeWatchedFiles".to_owned(),
+            register_options: serde_json::to_value(watchers).ok(),
+        };
+        if let Err(err) = self.client.register_capability(vec![registration]).await {
+            debug!("client does not support watching files: {err}");
+        }
+        self.start_indexing(&self.base_config().await.workspace_index)
+            .await;
         self.client
             .log_message(MessageType::INFO, "llm-ls initialized")
             .await;
         info!("initialized language server");
     }
 
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        match parse_client_config(params.settings) {
+            Ok(config) => {
+                *self.client_config.write().await = config;
+                info!("configuration changed");
+            }
+            Err(err) => error!("ignoring invalid configuration: {err}"),
+        }
+        self.start_indexing(&self.base_config().await.workspace_index)
+            .await;
+    }
+
+    async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        for change in params.changes {
+            let Ok(path) = change.uri.to_file_path() else {
+                continue;
+            };
+            if path.ends_with(PROJECT_CONFIG_FILE_NAME) {
+                info!("{} changed", path.display());
+                self.project_configs.write().await.invalidate(&path);
+            } else {
+                self.update_index(path).await;
+            }
+        }
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         let uri = params.text_document.uri.to_string();
         if uri == "file:///" {
@@ -700,12 +1490,23 @@ impl LanguageServer for LlmService {
             .log_message(MessageType::INFO, format!("{uri} saved"))
             .await;
         info!("{uri} saved");
+        if let Ok(path) = params.text_document.uri.to_file_path() {
+            self.update_index(path).await;
+        }
     }
 
     // TODO:
     // textDocument/didClose
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
         let uri = params.text_document.uri.to_string();
+        let in_flight_request = self
+            .in_flight_requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&uri);
+        if let Some((_, cancellation_token)) = in_flight_request {
+            cancellation_token.cancel();
+        }
         self.client
             .log_message(MessageType::INFO, format!("{uri} closed"))
             .await;
@@ -714,6 +1515,11 @@ impl LanguageServer for LlmService {
 
     async fn shutdown(&self) -> LspResult<()> {
         debug!("shutdown");
+        for index in self.workspace_indexes.read().await.iter() {
+            if let Err(err) = index.save(&self.cache_dir) {
+                error!("error saving index of {}: {err}", index.root().display());
+            }
+        }
         Ok(())
     }
 }
@@ -769,6 +1575,13 @@ async fn main() {
         position_encoding: Arc::new(RwLock::new(document::PositionEncodingKind::Utf16)),
         document_map: Arc::new(RwLock::new(HashMap::new())),
         http_client,
+        in_flight_requests: Arc::new(Mutex::new(HashMap::new())),
+        completion_cache: Arc::new(Mutex::new(CompletionCache::default())),
+        backends: Arc::new(BackendRegistry::default()),
+        client_config: Arc::new(RwLock::new(LlmLsConfigOverrides::default())),
+        project_configs: Arc::new(RwLock::new(ProjectConfigs::default())),
+        workspace_indexes: Arc::new(RwLock::new(vec![])),
+        index_params: Arc::new(RwLock::new(None)),
         unsafe_http_client,
         workspace_folders: Arc::new(RwLock::new(None)),
         tokenizer_map: Arc::new(RwLock::new(HashMap::new())),
@@ -781,6 +1594,7 @@ async fn main() {
     .custom_method("llm-ls/getCompletions", LlmService::get_completions)
     .custom_method("llm-ls/acceptCompletion", LlmService::accept_completion)
     .custom_method("llm-ls/rejectCompletion", LlmService::reject_completion)
+    .custom_method(InlineCompletion::METHOD, LlmService::inline_completion)
     .finish();
 
     if let Some(port) = args.socket {
@@ -799,3 +1613,450 @@ async fn main() {
         Server::new(stdin, stdout, socket).serve(service).await;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use custom_types::llm_ls::Backend;
+
+    #[tokio::test]
+    async fn test_in_flight_request_superseded() {
+        let requests: InFlightRequests = Arc::new(Mutex::new(HashMap::new()));
+        let uri = "file:///test.rs".to_owned();
+
+        let first = InFlightRequest::register(requests.clone(), uri.clone(), Uuid::new_v4());
+        let second = InFlightRequest::register(requests.clone(), uri.clone(), Uuid::new_v4());
+        assert!(first.cancellation_token.is_cancelled());
+        assert!(!second.cancellation_token.is_cancelled());
+
+        drop(first);
+        assert!(requests.lock().unwrap().contains_key(&uri));
+        drop(second);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_client_config() {
+        let settings = serde_json::json!({ "model": "bigcode/starcoder", "backend": "tgi", "url": "http://localhost:8080" });
+        let config =
+            parse_client_config(serde_json::json!({ "llm-ls": settings.clone() })).unwrap();
+        assert_eq!(config.model.as_deref(), Some("bigcode/starcoder"));
+        assert!(matches!(config.backend, Some(Backend::Tgi { .. })));
+        let config = parse_client_config(settings).unwrap();
+        assert_eq!(config.model.as_deref(), Some("bigcode/starcoder"));
+        let config = parse_client_config(Value::Null).unwrap();
+        assert!(config.model.is_none() && config.backend.is_none());
+        let config =
+            parse_client_config(serde_json::json!({ "model": "bigcode/starcoder" })).unwrap();
+        assert!(config.backend.is_none());
+
+        // invalid backends must not fall back to the default one
+        for settings in [
+            serde_json::json!({ "backend": "tgii", "url": "http://localhost:8080" }),
+            serde_json::json!({ "backend": "tgi" }),
+            serde_json::json!({ "backend": "ollama", "url": 3 }),
+        ] {
+            assert!(parse_client_config(settings.clone()).is_err(), "{settings}");
+        }
+        let params = serde_json::from_value::<GetCompletionsParams>(serde_json::json!({
+            "textDocument": { "uri": "file:///test.py" },
+            "position": { "line": 0, "character": 0 },
+            "backend": "tgii",
+            "url": "http://localhost:8080",
+        }));
+        assert!(params.is_err());
+    }
+
+    #[test]
+    fn test_get_completions_params_overrides() {
+        let params: GetCompletionsParams = serde_json::from_value(serde_json::json!({
+            "textDocument": { "uri": "file:///test.py" },
+            "position": { "line": 0, "character": 0 },
+            "model": "codellama:7b",
+            "backend": "ollama",
+            "url": "http://localhost:11434",
+            "contextWindow": 2048,
+        }))
+        .unwrap();
+        let mut config = LlmLsConfig::default();
+        config.apply(params.config);
+        assert_eq!(config.model, "codellama:7b");
+        assert_eq!(config.context_window, 2048);
+        assert!(
+            matches!(config.backend, Backend::Ollama { url } if url == "http://localhost:11434")
+        );
+        assert_eq!(
+            config.tokens_to_clear,
+            LlmLsConfig::default().tokens_to_clear
+        );
+
+        let params: GetCompletionsParams = serde_json::from_value(serde_json::json!({
+            "textDocument": { "uri": "file:///test.py" },
+            "position": { "line": 0, "character": 0 },
+        }))
+        .unwrap();
+        assert!(params.config.backend.is_none());
+    }
+
+    #[test]
+    fn test_preset_overrides() {
+        let overrides: LlmLsConfigOverrides = serde_json::from_value(serde_json::json!({
+            "preset": "codellama",
+            "tokensToClear": ["<EOT>", "</s>"],
+        }))
+        .unwrap();
+        let mut config = LlmLsConfig::default();
+        config.apply(overrides);
+        assert_eq!(config.fim.prefix, "<PRE> ");
+        assert_eq!(config.fim.suffix, " <SUF>");
+        assert_eq!(config.fim.middle, " <MID>");
+        assert_eq!(config.stop_tokens, vec!["<EOT>"]);
+        assert_eq!(config.tokens_to_clear, vec!["<EOT>", "</s>"]);
+
+        // a preset in another layer only replaces the FIM tokens and the fields of presets
+        let mut config = LlmLsConfig::default();
+        config.apply(
+            serde_json::from_value(serde_json::json!({
+                "preset": "starcoder2",
+                "fim": {
+                    "enabled": true,
+                    "prefix": "<fim_prefix>",
+                    "middle": "<fim_middle>",
+                    "suffix": "<fim_suffix>",
+                    "prefixRatio": 0.75,
+                    "maxSuffixLines": 10,
+                },
+            }))
+            .unwrap(),
+        );
+        config
+            .apply(serde_json::from_value(serde_json::json!({ "preset": "starcoder2" })).unwrap());
+        assert_eq!(config.fim.prefix_ratio, 0.75);
+        assert_eq!(config.fim.max_suffix_lines, Some(10));
+        assert_eq!(config.fim.file_separator.as_deref(), Some("<file_sep>"));
+        assert!(config
+            .fim
+            .template
+            .as_deref()
+            .is_some_and(|template| template.starts_with("<repo_name>")));
+        config
+            .apply(serde_json::from_value(serde_json::json!({ "preset": "qwen-coder" })).unwrap());
+        assert_eq!(config.fim.prefix, "<|fim_prefix|>");
+        assert_eq!(config.fim.file_separator.as_deref(), Some("<|file_sep|>"));
+        assert!(config
+            .fim
+            .template
+            .as_deref()
+            .is_some_and(|template| template.starts_with("<|repo_name|>")));
+        config.fim.template = Some("{prefix}<CURSOR>{suffix}".to_owned());
+        config.apply(serde_json::from_value(serde_json::json!({ "preset": "codellama" })).unwrap());
+        assert_eq!(config.fim.prefix, "<PRE> ");
+        assert_eq!(
+            config.fim.template.as_deref(),
+            Some("{prefix}<CURSOR>{suffix}")
+        );
+        assert!(config.fim.file_separator.is_none());
+    }
+
+    #[test]
+    fn test_pending_token_len() {
+        let tokens_to_clear = vec!["<|endoftext|>".to_owned(), "<EOT>".to_owned()];
+        assert_eq!(pending_token_len("return a<|endof", &tokens_to_clear), 7);
+        assert_eq!(pending_token_len("return a<", &tokens_to_clear), 1);
+        assert_eq!(pending_token_len("return a", &tokens_to_clear), 0);
+        // whole tokens are already cleared
+        assert_eq!(pending_token_len("return a<EOT>", &tokens_to_clear), 0);
+    }
+
+    #[tokio::test]
+    async fn test_should_complete_mid_word() {
+        let suppress = SuppressParams {
+            strings: true,
+            comments: true,
+        };
+        let document = Document::open("python", "def foo(a):\n    bar\nfoo(abc, )\n")
+            .await
+            .unwrap();
+        let complete = |line, character| {
+            should_complete(
+                &document,
+                Position::new(line, character),
+                &suppress,
+                document::PositionEncodingKind::Utf16,
+            )
+            .unwrap()
+        };
+        assert_eq!(complete(2, 6), Err(SkipReason::MidWord));
+        assert_eq!(complete(2, 9), Ok(CompletionType::SingleLine));
+        assert_eq!(complete(1, 4), Err(SkipReason::MidWord));
+    }
+
+    #[test]
+    fn test_cleared_span() {
+        let tokens_to_clear = vec!["<fim_middle>".to_owned(), "<|endoftext|>".to_owned()];
+        assert_eq!(
+            cleared_span("<fim_middle>return a\nb<|endoftext|>", &tokens_to_clear, 8),
+            12..20
+        );
+        assert_eq!(
+            cleared_span("ret<fim_middle>urn", &tokens_to_clear, 6),
+            0..18
+        );
+        assert_eq!(cleared_span("<fim_middle>", &tokens_to_clear, 0), 0..0);
+    }
+
+    #[test]
+    fn test_stop_sequences() {
+        assert_eq!(
+            stop_sequences(
+                &CompletionType::SingleLine,
+                LanguageId::Python,
+                vec!["<|endoftext|>".to_owned()]
+            ),
+            vec!["<|endoftext|>", "\n"]
+        );
+        assert_eq!(
+            stop_sequences(
+                &CompletionType::MultiLine,
+                LanguageId::Go,
+                vec!["\nfunc ".to_owned()]
+            ),
+            vec!["\nfunc ", "\ntype "]
+        );
+    }
+
+    #[test]
+    fn test_candidate_batches() {
+        assert_eq!(candidate_batches(1, usize::MAX), vec![1]);
+        assert_eq!(candidate_batches(3, usize::MAX), vec![3]);
+        assert_eq!(candidate_batches(3, 2), vec![2, 1]);
+        assert_eq!(candidate_batches(4, 2), vec![2, 2]);
+        assert_eq!(candidate_batches(3, 1), vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_rank_generations() {
+        let generation = |generated_text: &str, logprobs: Option<Vec<f32>>| Generation {
+            generated_text: generated_text.to_owned(),
+            tokens: logprobs.map(|logprobs| {
+                logprobs
+                    .into_iter()
+                    .map(|logprob| Token {
+                        text: String::new(),
+                        logprob,
+                    })
+                    .collect()
+            }),
+        };
+        let mut generations = vec![
+            generation("a", None),
+            generation("b", Some(vec![-1., -3.])),
+            generation("c", None),
+            generation("d", Some(vec![-0.5])),
+        ];
+        rank_generations(&mut generations);
+        let texts = generations
+            .iter()
+            .map(|g| g.generated_text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["d", "b", "a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_format_generations_dedup() {
+        let document = Document::open("python", "x = \n").await.unwrap();
+        let generations = ["1\nfoo", "1", "2"]
+            .into_iter()
+            .map(|generated_text| Generation {
+                generated_text: generated_text.to_owned(),
+                ..Default::default()
+            })
+            .collect();
+        let completions = format_generations(
+            generations,
+            None,
+            &[],
+            CompletionType::SingleLine,
+            &document,
+            Position::new(0, 4),
+            document::PositionEncodingKind::Utf16,
+        );
+        let texts = completions
+            .iter()
+            .map(|c| c.generated_text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["1", "2"]);
+    }
+
+    #[tokio::test]
+    async fn test_build_cross_file_context() {
+        let uri = "file:///src/main.py";
+        let document_map = HashMap::from([
+            (
+                uri.to_owned(),
+                Document::open(
+                    "python",
+                    "import db
+
+conn = db.connect(url)
+",
+                )
+                .await
+                .unwrap(),
+            ),
+            (
+                "file:///src/db.py".to_owned(),
+                Document::open("python", "def connect(url):\n    return Connection(url)\n")
+                    .await
+                    .unwrap(),
+            ),
+        ]);
+        let mut config = LlmLsConfig::default();
+        config.apply(
+            parse_client_config(serde_json::json!({
+                "crossFileContext": { "enabled": true, "windowSize": 0 },
+            }))
+            .unwrap(),
+        );
+        assert_eq!(config.cross_file_context.window_size, 1);
+        let (context, snippets) = build_cross_file_context(
+            Position::new(2, 0),
+            uri,
+            &document_map,
+            &[],
+            &[],
+            &config,
+            None,
+        )
+        .unwrap();
+        assert!(context.is_empty() && snippets.is_empty());
+        let (_, snippets) = build_cross_file_context(
+            Position::new(2, 22),
+            uri,
+            &document_map,
+            &[],
+            &[],
+            &config,
+            None,
+        )
+        .unwrap();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].content, "def connect(url):\n");
+    }
+
+    #[tokio::test]
+    async fn test_build_cross_file_context_workspace_index() {
+        let dir = std::env::temp_dir().join(format!("llm-ls-context-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main_text = "import db\n\nconn = db.connect(url)\n";
+        let db_text = "def connect(url):\n    return Connection(url)\n";
+        std::fs::write(dir.join("main.py"), main_text).unwrap();
+        std::fs::write(dir.join("db.py"), db_text).unwrap();
+        let mut config = LlmLsConfig::default();
+        config.workspace_index.enabled = true;
+        let mut index = WorkspaceIndex::load(&dir, dir.clone());
+        index.refresh(&config.workspace_index);
+
+        let uri = Url::from_file_path(dir.join("main.py"))
+            .unwrap()
+            .to_string();
+        let db_uri = Url::from_file_path(dir.join("db.py")).unwrap().to_string();
+        let document_map = HashMap::from([
+            (
+                uri.clone(),
+                Document::open("python", main_text).await.unwrap(),
+            ),
+            (db_uri, Document::open("python", db_text).await.unwrap()),
+        ]);
+        let context = |config: &LlmLsConfig| {
+            build_cross_file_context(
+                Position::new(2, 22),
+                &uri,
+                &document_map,
+                std::slice::from_ref(&index),
+                std::slice::from_ref(&dir),
+                config,
+                None,
+            )
+            .unwrap()
+            .1
+        };
+        // open documents other than the current one come from the index
+        let snippets = context(&config);
+        assert_eq!(
+            snippets
+                .iter()
+                .map(|snippet| snippet.path.as_str())
+                .collect::<Vec<_>>(),
+            vec!["db.py"]
+        );
+        // rather than from both sources
+        config.cross_file_context.enabled = true;
+        assert_eq!(context(&config).len(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_completions_partial_failure() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(mock_server::serve(listener));
+
+        let config = LlmLsConfig {
+            model: "flaky".to_owned(),
+            backend: Backend::HuggingFace { url },
+            num_candidates: 3,
+            ..Default::default()
+        };
+        let prompt = Prompt {
+            text: "def add(a, b):".to_owned(),
+            suffix: None,
+            snippets: vec![],
+            token_counts: Default::default(),
+        };
+        let generations = request_completions(
+            &reqwest::Client::new(),
+            &crate::backend::HuggingFaceBackend,
+            &prompt,
+            &config,
+            Ide::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(generations.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_backend() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(mock_server::serve(listener));
+
+        let mut config = LlmLsConfig {
+            model: "claude-3-5-haiku-latest".to_owned(),
+            backend: Backend::Anthropic { url },
+            api_token: Some("sk-ant".to_owned()),
+            ..Default::default()
+        };
+        let http_client = reqwest::Client::new();
+        let backend = crate::backend::AnthropicBackend;
+        let prompt = Prompt {
+            text: "def add(a, b):".to_owned(),
+            suffix: None,
+            snippets: vec![],
+            token_counts: Default::default(),
+        };
+        let generations =
+            request_completion(&http_client, &backend, &prompt, &config, Ide::default(), 1)
+                .await
+                .unwrap();
+        assert_eq!(generations[0].generated_text, "dummy");
+
+        config.api_token = None;
+        let err = request_completion(&http_client, &backend, &prompt, &config, Ide::default(), 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Anthropic(_)), "{err}");
+    }
+}
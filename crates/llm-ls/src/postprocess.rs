@@ -0,0 +1,400 @@
+use ropey::Rope;
+use tower_lsp::lsp_types::{Position, Range};
+use tree_sitter::{InputEdit, Point, Tree};
+
+use crate::document::{get_parser, Document, PositionEncodingKind};
+
+/// Maximum number of chars after the cursor compared with the end of a completion
+const MAX_OVERLAP_CHARS: usize = 256;
+
+/// Number of `ERROR` and `MISSING` nodes in a tree.
+fn error_count(tree: &Tree) -> usize {
+    let mut count = 0;
+    let mut cursor = tree.walk();
+    loop {
+        let node = cursor.node();
+        if node.is_error() || node.is_missing() {
+            count += 1;
+        }
+        // only subtrees containing errors are worth visiting
+        if node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return count;
+            }
+        }
+    }
+}
+
+/// Whether an `ERROR` or `MISSING` node of the tree overlaps the bytes `start..=end`.
+fn has_error_in(tree: &Tree, start: usize, end: usize) -> bool {
+    let mut cursor = tree.walk();
+    loop {
+        let node = cursor.node();
+        let overlaps = node.start_byte() <= end && node.end_byte() >= start;
+        if overlaps && (node.is_error() || node.is_missing()) {
+            return true;
+        }
+        // only subtrees containing errors are worth visiting
+        if overlaps && node.has_error() && cursor.goto_first_child() {
+            continue;
+        }
+        while !cursor.goto_next_sibling() {
+            if !cursor.goto_parent() {
+                return false;
+            }
+        }
+    }
+}
+
+/// Cursor position as a char index in `text` as well as a tree-sitter point, its character offset
+/// being counted in code units of `position_encoding`.
+pub(crate) fn cursor_position(
+    text: &Rope,
+    pos: Position,
+    position_encoding: PositionEncodingKind,
+) -> Option<(usize, Point)> {
+    let char_idx = encoded_char_idx(text, pos, position_encoding)?;
+    let row = pos.line as usize;
+    let point = Point {
+        row,
+        column: text.char_to_byte(char_idx) - text.line_to_byte(row),
+    };
+    Some((char_idx, point))
+}
+
+/// Whether a line is too common to tell that the completion repeats the code after the cursor,
+/// e.g. a closing bracket or a keyword such as `end`.
+fn is_generic_line(line: &str) -> bool {
+    line.trim()
+        .trim_matches(|c: char| c.is_ascii_punctuation())
+        .chars()
+        .all(char::is_alphabetic)
+}
+
+/// Cuts the completion before the first line, past its first one, repeating the first non-blank
+/// line after the cursor's one, indentation included. Lines made of punctuation or a single
+/// keyword are skipped, and the rest of the cursor's line is left to [`overlap_range`].
+fn trim_suffix_duplicate(text: &Rope, char_idx: usize, completion: &str) -> String {
+    let suffix = text.slice(char_idx..);
+    let Some(next_line) = suffix
+        .lines()
+        .skip(1)
+        .map(|line| line.to_string())
+        .find(|line| !line.trim().is_empty() && !is_generic_line(line))
+    else {
+        return completion.to_owned();
+    };
+    let next_line = next_line.trim_end();
+    let mut end = 0;
+    for (i, line) in completion.split_inclusive('\n').enumerate() {
+        if i > 0 && line.trim_end() == next_line {
+            let kept = &completion[..end];
+            // the line ending is already there when the cursor is at the end of its line
+            return if suffix
+                .chars()
+                .next()
+                .is_some_and(|c| c == '\n' || c == '\r')
+            {
+                kept.trim_end_matches(['\n', '\r']).to_owned()
+            } else {
+                kept.to_owned()
+            };
+        }
+        end += line.len();
+    }
+    completion.to_owned()
+}
+
+/// Trims a multi-line completion so that it does not overshoot the block being completed.
+///
+/// The completion is first cut before it starts repeating the code after the cursor. It is then
+/// inserted in a copy of the document, which is reparsed, to keep the longest prefix ending at
+/// a line boundary that neither adds syntax errors to the document nor contains any. The
+/// completion is returned as is when no such prefix exists, as the document may not be
+/// parseable while the user is typing.
+pub(crate) fn trim_multi_line(
+    document: &Document,
+    pos: Position,
+    completion: &str,
+    position_encoding: PositionEncodingKind,
+) -> String {
+    let Some((char_idx, point)) = cursor_position(&document.text, pos, position_encoding) else {
+        return completion.to_owned();
+    };
+    let completion = trim_suffix_duplicate(&document.text, char_idx, completion);
+    let (Some(tree), Ok(mut parser)) = (&document.tree, get_parser(document.language_id)) else {
+        return completion;
+    };
+    let start_byte = document.text.char_to_byte(char_idx);
+    let baseline = error_count(tree);
+
+    let mut ends = completion
+        .match_indices('\n')
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    ends.push(completion.len());
+    for &end in ends.iter().rev().filter(|&&end| end > 0) {
+        let candidate = &completion[..end];
+        let mut text = document.text.clone();
+        text.insert(char_idx, candidate);
+        let new_end_position = match candidate.rfind('\n') {
+            Some(last_line_start) => Point {
+                row: point.row + candidate.matches('\n').count(),
+                column: candidate.len() - last_line_start - 1,
+            },
+            None => Point {
+                row: point.row,
+                column: point.column + candidate.len(),
+            },
+        };
+        let mut edited = tree.clone();
+        edited.edit(&InputEdit {
+            start_byte,
+            old_end_byte: start_byte,
+            new_end_byte: start_byte + candidate.len(),
+            start_position: point,
+            old_end_position: point,
+            new_end_position,
+        });
+        let Some(new_tree) = parser.parse(text.to_string(), Some(&edited)) else {
+            break;
+        };
+        if error_count(&new_tree) <= baseline
+            && !has_error_in(&new_tree, start_byte, start_byte + candidate.len())
+        {
+            return candidate.to_owned();
+        }
+    }
+    completion
+}
+
+/// Char index of `pos` in `text`, its character offset being counted in code units of
+/// `position_encoding`.
+fn encoded_char_idx(
+    text: &Rope,
+    pos: Position,
+    position_encoding: PositionEncodingKind,
+) -> Option<usize> {
+    let row = pos.line as usize;
+    let line = text.get_line(row)?;
+    let character = pos.character as usize;
+    let col = match position_encoding {
+        PositionEncodingKind::Utf8 => line.byte_to_char(character.min(line.len_bytes())),
+        PositionEncodingKind::Utf16 => line.utf16_cu_to_char(character.min(line.len_utf16_cu())),
+        PositionEncodingKind::Utf32 => character.min(line.len_chars()),
+    };
+    Some(text.line_to_char(row) + col)
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Finds the longest text following the cursor that the completion ends with, e.g. closing
+/// brackets generated again by the model, and returns the range it spans so that the completion
+/// replaces it rather than duplicating it. Single line completions are only compared to the rest
+/// of the cursor's line.
+///
+/// Overlaps made of whitespace only or cutting through an identifier are ignored.
+pub(crate) fn overlap_range(
+    document: &Document,
+    pos: Position,
+    completion: &str,
+    single_line: bool,
+    position_encoding: PositionEncodingKind,
+) -> Option<Range> {
+    let text = &document.text;
+    let char_idx = encoded_char_idx(text, pos, position_encoding)?;
+    let mut suffix = text
+        .slice(char_idx..(char_idx + MAX_OVERLAP_CHARS).min(text.len_chars()))
+        .to_string();
+    if single_line {
+        suffix.truncate(suffix.find(['\n', '\r']).unwrap_or(suffix.len()));
+    }
+    let overlap = suffix
+        .char_indices()
+        .map(|(idx, c)| idx + c.len_utf8())
+        .rev()
+        .map(|end| &suffix[..end])
+        .find(|&overlap| {
+            let Some(before) = completion.strip_suffix(overlap) else {
+                return false;
+            };
+            let splits = |a: Option<char>, b: Option<char>| {
+                a.is_some_and(is_identifier_char) && b.is_some_and(is_identifier_char)
+            };
+            !overlap.trim().is_empty()
+                && !splits(before.chars().next_back(), overlap.chars().next())
+                && !splits(
+                    overlap.chars().next_back(),
+                    suffix[overlap.len()..].chars().next(),
+                )
+        })?;
+
+    let end_char_idx = char_idx + overlap.chars().count();
+    let end_line_idx = text.char_to_line(end_char_idx);
+    let end_line = text.line(end_line_idx);
+    let end_col = end_char_idx - text.line_to_char(end_line_idx);
+    let end_character = match position_encoding {
+        PositionEncodingKind::Utf8 => end_line.char_to_byte(end_col),
+        PositionEncodingKind::Utf16 => end_line.char_to_utf16_cu(end_col),
+        PositionEncodingKind::Utf32 => end_col,
+    };
+    Some(Range::new(
+        pos,
+        Position::new(end_line_idx as u32, end_character as u32),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trim_multi_line() {
+        // closes the list twice
+        let document = Document::open("python", "values = [\n    \n]\n")
+            .await
+            .unwrap();
+        let completion = trim_multi_line(
+            &document,
+            Position::new(1, 4),
+            "1,\n    2,\n    3]\nprint(values",
+            PositionEncodingKind::Utf16,
+        );
+        assert_eq!(completion, "1,\n    2,");
+
+        // repeats the code after the cursor
+        let document = Document::open(
+            "python",
+            "def add(a, b):\n    \n\ndef sub(a, b):\n    return a - b\n",
+        )
+        .await
+        .unwrap();
+        let completion = trim_multi_line(
+            &document,
+            Position::new(1, 4),
+            "return a + b\n\ndef sub(a, b):\n    return a - b\n",
+            PositionEncodingKind::Utf16,
+        );
+        assert_eq!(completion, "return a + b");
+
+        // the closing brace after the cursor is too common to cut the completion on it
+        let document = Document::open("rust", "fn foo() {\n    \n}\n")
+            .await
+            .unwrap();
+        let completion = "if x {\n        y();\n    }\n    z();";
+        assert_eq!(
+            trim_multi_line(
+                &document,
+                Position::new(1, 4),
+                completion,
+                PositionEncodingKind::Utf16
+            ),
+            completion
+        );
+
+        // the line after the cursor is repeated at another indentation level
+        let document = Document::open("python", "def f(x):\n    \n    return x\n")
+            .await
+            .unwrap();
+        let completion = "if x:\n        return x\n    y = 1";
+        assert_eq!(
+            trim_multi_line(
+                &document,
+                Position::new(1, 4),
+                completion,
+                PositionEncodingKind::Utf16
+            ),
+            completion
+        );
+    }
+
+    #[test]
+    fn test_cursor_position() {
+        let text = Rope::from_str("x\nlet s = \"😀\"; y\n");
+        let point = |column| Point { row: 1, column };
+        // after the emoji, 2 UTF-16 code units and 4 bytes long
+        assert_eq!(
+            cursor_position(&text, Position::new(1, 11), PositionEncodingKind::Utf16),
+            Some((12, point(13)))
+        );
+        assert_eq!(
+            cursor_position(&text, Position::new(1, 13), PositionEncodingKind::Utf8),
+            Some((12, point(13)))
+        );
+        assert_eq!(
+            cursor_position(&text, Position::new(1, 10), PositionEncodingKind::Utf32),
+            Some((12, point(13)))
+        );
+        assert_eq!(
+            cursor_position(&text, Position::new(2, 0), PositionEncodingKind::Utf16),
+            Some((text.len_chars(), Point { row: 2, column: 0 }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overlap_range() {
+        let document = Document::open("python", "print(add(1, ))\nfoo\n")
+            .await
+            .unwrap();
+        let pos = Position::new(0, 13);
+        assert_eq!(
+            overlap_range(&document, pos, "2))", true, PositionEncodingKind::Utf16),
+            Some(Range::new(pos, Position::new(0, 15)))
+        );
+        assert_eq!(
+            overlap_range(&document, pos, "2)", true, PositionEncodingKind::Utf16),
+            Some(Range::new(pos, Position::new(0, 14)))
+        );
+        assert_eq!(
+            overlap_range(&document, pos, "2", true, PositionEncodingKind::Utf16),
+            None
+        );
+        assert_eq!(
+            overlap_range(
+                &document,
+                pos,
+                "2))\nfoo",
+                true,
+                PositionEncodingKind::Utf16
+            ),
+            None
+        );
+        assert_eq!(
+            overlap_range(
+                &document,
+                pos,
+                "2))\nfoo",
+                false,
+                PositionEncodingKind::Utf16
+            ),
+            Some(Range::new(pos, Position::new(1, 3)))
+        );
+
+        // `fo` would otherwise overlap with the beginning of `foo`
+        let document = Document::open("python", "x = \nfoo = 1\n").await.unwrap();
+        let pos = Position::new(0, 4);
+        assert_eq!(
+            overlap_range(&document, pos, "1\nfo", false, PositionEncodingKind::Utf16),
+            None
+        );
+
+        // the cursor's character offset is counted in code units of the position encoding
+        let document = Document::open("python", "f(\"😊\", )\n").await.unwrap();
+        for (position_encoding, character) in [
+            (PositionEncodingKind::Utf8, 10),
+            (PositionEncodingKind::Utf16, 8),
+            (PositionEncodingKind::Utf32, 7),
+        ] {
+            let pos = Position::new(0, character);
+            assert_eq!(
+                overlap_range(&document, pos, "1)", true, position_encoding),
+                Some(Range::new(pos, Position::new(0, character + 1)))
+            );
+        }
+    }
+}
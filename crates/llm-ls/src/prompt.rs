@@ -0,0 +1,924 @@
+use custom_types::llm_ls::{FimParams, PromptTokenCounts};
+use ropey::Rope;
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Instant;
+use tokenizers::Tokenizer;
+use tower_lsp::lsp_types::Position;
+use tracing::info;
+use tree_sitter::{Node, Point, Tree};
+
+use crate::context::Snippet;
+use crate::document::Document;
+use crate::error::Result;
+
+pub(crate) fn count_tokens(tokenizer: Option<&Tokenizer>, text: &str) -> Result<usize> {
+    match tokenizer {
+        Some(tokenizer) => Ok(tokenizer.encode(text, false)?.len()),
+        None => Ok(text.len()),
+    }
+}
+
+/// Information about the document added to the prompt besides the code around the cursor.
+pub(crate) struct PromptContext {
+    /// Snippets of other files, see [`crate::build_cross_file_context`]
+    pub(crate) cross_file: String,
+    /// Snippets making up `cross_file`
+    pub(crate) snippets: Vec<Snippet>,
+    pub(crate) filename: String,
+    pub(crate) repo: String,
+}
+
+/// Substitutes the placeholders of a FIM template in a single pass, so that placeholders
+/// appearing in the substituted code are left untouched.
+fn render_fim_template(
+    template: &str,
+    prefix: &str,
+    suffix: &str,
+    context: &PromptContext,
+) -> String {
+    let mut rendered = String::with_capacity(template.len() + prefix.len() + suffix.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find('}').map(|end| end + 1).unwrap_or(rest.len());
+        let value = match &rest[..end] {
+            "{prefix}" => prefix,
+            "{suffix}" => suffix,
+            "{filename}" => &context.filename,
+            "{repo}" => &context.repo,
+            "{context}" => &context.cross_file,
+            _ => {
+                rendered.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        rendered.push_str(value);
+        rest = &rest[end..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Side of the cursor a list of lines comes from. Lines are always ordered by distance to the
+/// cursor, the first one being the part of the cursor's line on that side.
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Before,
+    After,
+}
+
+/// Maximum number of lines kept from the header of a scope enclosing the cursor
+const MAX_HEADER_LINES: usize = 4;
+
+/// Structure of the document around the cursor, used to truncate the prompt at node boundaries
+/// rather than at arbitrary lines.
+struct Scopes {
+    /// Lines at which the children of the nodes enclosing the cursor start
+    starts: BTreeSet<usize>,
+    /// Lines at which the children of the nodes enclosing the cursor end
+    ends: BTreeSet<usize>,
+    /// Headers of the nodes enclosing the cursor, e.g. function signatures, innermost first
+    headers: Vec<RangeInclusive<usize>>,
+    /// Top level imports before the cursor
+    imports: Vec<RangeInclusive<usize>>,
+}
+
+impl Scopes {
+    fn new(tree: &Tree, text: &Rope, pos: Position) -> Self {
+        let row = pos.line as usize;
+        let column = text
+            .get_line(row)
+            .map(|line| line.char_to_byte((pos.character as usize).min(line.len_chars())))
+            .unwrap_or_default();
+        let point = Point { row, column };
+        let root = tree.root_node();
+        let mut starts = BTreeSet::new();
+        let mut ends = BTreeSet::new();
+        let mut headers = vec![];
+        let mut node = root.descendant_for_point_range(point, point);
+        while let Some(scope) = node {
+            let mut cursor = scope.walk();
+            for child in scope.named_children(&mut cursor) {
+                starts.insert(child.start_position().row);
+                ends.insert(end_row(&child));
+            }
+            let start = scope.start_position().row;
+            // scopes are the nodes with a body, e.g. functions or classes, their header goes up
+            // to the body, including its first line when it starts after the signature, e.g.
+            // with a brace
+            if let Some(body) = scope.child_by_field_name("body").filter(|_| start < row) {
+                let body_start = body.start_position();
+                let indent = text
+                    .line(body_start.row)
+                    .bytes()
+                    .take_while(u8::is_ascii_whitespace)
+                    .count();
+                let end = if body_start.column > indent {
+                    body_start.row
+                } else {
+                    body_start.row.saturating_sub(1)
+                };
+                headers.push(start..=end.clamp(start, start + MAX_HEADER_LINES - 1).min(row - 1));
+            }
+            node = scope.parent();
+        }
+        let mut cursor = root.walk();
+        let imports = root
+            .named_children(&mut cursor)
+            .take_while(|child| end_row(child) < row)
+            .filter(|child| is_import(child.kind()))
+            .map(|child| child.start_position().row..=end_row(&child))
+            .collect();
+        Self {
+            starts,
+            ends,
+            headers,
+            imports,
+        }
+    }
+}
+
+/// Last line spanned by a node, not counting a trailing line ending.
+fn end_row(node: &Node) -> usize {
+    let end = node.end_position();
+    if end.column == 0 && end.row > node.start_position().row {
+        end.row - 1
+    } else {
+        end.row
+    }
+}
+
+fn is_import(kind: &str) -> bool {
+    kind.contains("import")
+        || matches!(
+            kind,
+            "use_declaration"
+                | "extern_crate_declaration"
+                | "preproc_include"
+                | "using_directive"
+                | "package_clause"
+                | "package_declaration"
+        )
+}
+
+/// Lines kept on one side of the cursor.
+struct Fitted {
+    text: String,
+    token_count: usize,
+    /// Number of lines kept
+    len: usize,
+    /// Whether some lines were left out for lack of budget
+    truncated: bool,
+}
+
+/// Lines on one side of the cursor along with their token counts.
+struct Lines {
+    side: Side,
+    cursor_line_idx: usize,
+    lines: Vec<String>,
+    token_counts: Vec<usize>,
+}
+
+impl Lines {
+    /// Collects the lines on one side of the cursor, until their token count exceeds `budget`
+    /// by a margin accounting for tokens merging across line boundaries or `max_lines` lines
+    /// besides the cursor's are collected.
+    fn collect(
+        document: &Document,
+        pos: Position,
+        side: Side,
+        tokenizer: Option<&Arc<Tokenizer>>,
+        budget: usize,
+        max_lines: Option<usize>,
+    ) -> Result<Self> {
+        let text = &document.text;
+        let cursor_line_idx = pos.line as usize;
+        let mut lines = vec![];
+        let mut token_counts = vec![];
+        if let Some(line) = text.get_line(cursor_line_idx) {
+            let col = (pos.character as usize).clamp(0, line.len_chars());
+            let line = match side {
+                Side::Before => line.slice(..col),
+                Side::After => line.slice(col..),
+            }
+            .to_string();
+            token_counts.push(count_tokens(tokenizer.map(AsRef::as_ref), &line)?);
+            lines.push(line);
+        }
+        let max_token_count = budget + budget / 8;
+        let mut token_count = token_counts.first().copied().unwrap_or_default();
+        let mut line_idx = cursor_line_idx;
+        while token_count <= max_token_count
+            && max_lines.is_none_or(|max_lines| lines.len() <= max_lines)
+        {
+            line_idx = match side {
+                Side::Before => match line_idx.checked_sub(1) {
+                    Some(line_idx) => line_idx,
+                    None => break,
+                },
+                Side::After => line_idx + 1,
+            };
+            let Some(line) = text.get_line(line_idx) else {
+                break;
+            };
+            let count = match tokenizer {
+                Some(tokenizer) => document.line_token_count(tokenizer, line_idx)?,
+                None => line.len_bytes(),
+            };
+            token_count += count;
+            token_counts.push(count);
+            lines.push(line.to_string());
+        }
+        Ok(Self {
+            side,
+            cursor_line_idx,
+            lines,
+            token_counts,
+        })
+    }
+
+    /// Text of the first `len` lines, in document order.
+    fn join(&self, len: usize) -> String {
+        match self.side {
+            Side::Before => self.lines[..len].iter().rev().map(String::as_str).collect(),
+            Side::After => self.lines[..len].concat(),
+        }
+    }
+
+    /// Finds how many lines fit in `budget` tokens.
+    ///
+    /// The lines are encoded at once, rather than summing the counts of the individual lines
+    /// which can be off at line boundaries, and the offsets of the tokens are binary searched
+    /// for the line boundary closest to the cursor that fits.
+    fn fit(&self, budget: usize, tokenizer: Option<&Tokenizer>) -> Result<Fitted> {
+        let mut len = 0;
+        let mut estimate = 0;
+        for count in &self.token_counts {
+            if estimate + count > budget + budget / 8 {
+                break;
+            }
+            estimate += count;
+            len += 1;
+        }
+        let Some(tokenizer) = tokenizer else {
+            // byte counts are exact
+            while estimate > budget {
+                len -= 1;
+                estimate -= self.token_counts[len];
+            }
+            return Ok(Fitted {
+                text: self.join(len),
+                token_count: estimate,
+                len,
+                truncated: len < self.lines.len(),
+            });
+        };
+
+        let text = self.join(len);
+        let encoding = tokenizer.encode(text.as_str(), false)?;
+        let offsets = encoding.get_offsets();
+        if offsets.len() <= budget {
+            return Ok(Fitted {
+                text,
+                token_count: offsets.len(),
+                len,
+                truncated: len < self.lines.len(),
+            });
+        }
+        // `boundaries[i]` is the byte offset separating the first `i` lines from the others
+        let mut boundaries = Vec::with_capacity(len + 1);
+        let mut boundary = 0;
+        boundaries.push(boundary);
+        for line in &self.lines[..len] {
+            boundary += line.len();
+            boundaries.push(boundary);
+        }
+        let token_count = |kept: usize| match self.side {
+            Side::Before => {
+                let start = text.len() - boundaries[kept];
+                offsets.len() - offsets.partition_point(|&(token_start, _)| token_start < start)
+            }
+            Side::After => offsets.partition_point(|&(_, token_end)| token_end <= boundaries[kept]),
+        };
+        // the token count grows with the number of lines kept, find the last one within budget
+        let kept = (0..=len).collect::<Vec<_>>();
+        let kept = kept.partition_point(|&kept| token_count(kept) <= budget) - 1;
+        let text = match self.side {
+            Side::Before => text[text.len() - boundaries[kept]..].to_owned(),
+            Side::After => text[..boundaries[kept]].to_owned(),
+        };
+        // a token spanning the boundary with the lines left out is not counted above, while
+        // the part of it that is kept makes up tokens of its own
+        let token_count = count_tokens(Some(tokenizer), &text)?;
+        Ok(Fitted {
+            text,
+            token_count,
+            len: kept,
+            truncated: true,
+        })
+    }
+
+    /// Finds how many lines fit in `budget` tokens like [`Lines::fit`], then moves the cut back
+    /// to the closest boundary between nodes so that no node far from the cursor is left half
+    /// included.
+    ///
+    /// Before the cursor, the headers of the enclosing scopes and the imports that were cut off
+    /// are kept as well, within half of the budget.
+    fn fit_scopes(
+        &self,
+        budget: usize,
+        tokenizer: Option<&Tokenizer>,
+        scopes: Option<&Scopes>,
+        text: &Rope,
+    ) -> Result<Fitted> {
+        let fitted = self.fit(budget, tokenizer)?;
+        let Some(scopes) = scopes.filter(|_| fitted.truncated && fitted.len > 0) else {
+            return Ok(fitted);
+        };
+        let cursor = self.cursor_line_idx;
+        match self.side {
+            Side::Before => {
+                let mut pinned = BTreeSet::new();
+                let mut pinned_token_count = 0;
+                for rows in scopes.headers.iter().chain(&scopes.imports) {
+                    let rows = rows
+                        .clone()
+                        .filter(|row| !pinned.contains(row))
+                        .collect::<Vec<_>>();
+                    let rows_text = rows
+                        .iter()
+                        .map(|&row| text.line(row).to_string())
+                        .collect::<String>();
+                    let token_count = count_tokens(tokenizer, &rows_text)?;
+                    if pinned_token_count + token_count <= budget / 2 {
+                        pinned_token_count += token_count;
+                        pinned.extend(rows);
+                    }
+                }
+                let fitted = self.fit(budget - pinned_token_count, tokenizer)?;
+                let mut len = fitted.len;
+                if fitted.truncated && len > 0 {
+                    if let Some(row) = scopes.starts.range(cursor + 1 - len..=cursor).next() {
+                        len = cursor + 1 - row;
+                    }
+                }
+                let first_kept = cursor + 1 - len;
+                let mut kept = pinned
+                    .range(..first_kept)
+                    .map(|&row| text.line(row).to_string())
+                    .collect::<String>();
+                kept.push_str(&self.join(len));
+                Ok(Fitted {
+                    token_count: count_tokens(tokenizer, &kept)?,
+                    text: kept,
+                    len,
+                    truncated: true,
+                })
+            }
+            Side::After => {
+                let last_kept = cursor + fitted.len - 1;
+                let Some(row) = scopes.ends.range(cursor..=last_kept).next_back() else {
+                    return Ok(fitted);
+                };
+                let len = row - cursor + 1;
+                let kept = self.join(len);
+                Ok(Fitted {
+                    token_count: count_tokens(tokenizer, &kept)?,
+                    text: kept,
+                    len,
+                    truncated: true,
+                })
+            }
+        }
+    }
+}
+
+/// How the code around the cursor is laid out in the prompt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PromptLayout {
+    /// A single text, with the FIM tokens or template
+    Fim,
+    /// The code before the cursor and the code after it apart, for backends taking the suffix in
+    /// a field of its own
+    Split,
+    /// Like [`PromptLayout::Split`], with the cross-file snippets apart as well rather than
+    /// before the code
+    SplitSnippets,
+}
+
+pub(crate) struct Prompt {
+    /// Whole prompt, or the code before the cursor with the split layouts
+    pub(crate) text: String,
+    /// Code after the cursor with the split layouts, when FIM is enabled
+    pub(crate) suffix: Option<String>,
+    /// Cross-file snippets with the [`PromptLayout::SplitSnippets`] layout
+    pub(crate) snippets: Vec<Snippet>,
+    pub(crate) token_counts: PromptTokenCounts,
+}
+
+pub(crate) fn build_prompt(
+    pos: Position,
+    document: &Document,
+    fim: &FimParams,
+    context: &PromptContext,
+    tokenizer: Option<Arc<Tokenizer>>,
+    context_window: usize,
+    layout: PromptLayout,
+) -> Result<Prompt> {
+    let t = Instant::now();
+    let context_token_count = count_tokens(tokenizer.as_deref(), &context.cross_file)?;
+    let mut token_counts = PromptTokenCounts {
+        context: context_token_count,
+        ..Default::default()
+    };
+    let (prompt, suffix) = if fim.enabled {
+        // account for FIM tokens
+        let fim_token_count = match &fim.template {
+            _ if layout != PromptLayout::Fim => 0,
+            Some(template) => {
+                let empty = PromptContext {
+                    cross_file: String::new(),
+                    snippets: vec![],
+                    filename: context.filename.clone(),
+                    repo: context.repo.clone(),
+                };
+                count_tokens(
+                    tokenizer.as_deref(),
+                    &render_fim_template(template, "", "", &empty),
+                )?
+            }
+            None => 3,
+        };
+        let budget = context_window.saturating_sub(fim_token_count + context_token_count);
+        let prefix_budget = (budget as f32 * fim.prefix_ratio.clamp(0., 1.)) as usize;
+        let before_lines = Lines::collect(
+            document,
+            pos,
+            Side::Before,
+            tokenizer.as_ref(),
+            budget,
+            None,
+        )?;
+        let after_lines = Lines::collect(
+            document,
+            pos,
+            Side::After,
+            tokenizer.as_ref(),
+            budget,
+            fim.max_suffix_lines,
+        )?;
+        // the suffix gets its share as well as what the prefix did not use, what it does not use
+        // in turn goes back to the prefix
+        let scopes = document
+            .tree
+            .as_ref()
+            .map(|tree| Scopes::new(tree, &document.text, pos));
+        let fit = |lines: &Lines, budget| {
+            lines.fit_scopes(
+                budget,
+                tokenizer.as_deref(),
+                scopes.as_ref(),
+                &document.text,
+            )
+        };
+        let mut before = fit(&before_lines, prefix_budget)?;
+        let mut after = fit(&after_lines, budget.saturating_sub(before.token_count))?;
+        if before.truncated && before.token_count + after.token_count < budget {
+            before = fit(&before_lines, budget.saturating_sub(after.token_count))?;
+        }
+        // the text kept is counted again as a whole, which may exceed the budget it was fitted
+        // to, the prefix and then the suffix are refitted to lower budgets until both fit
+        let (mut before_budget, mut after_budget) = (before.token_count, after.token_count);
+        while before.token_count + after.token_count > budget {
+            let overshoot = before.token_count + after.token_count - budget;
+            if before_budget > 0 {
+                before_budget = before_budget.saturating_sub(overshoot);
+                before = fit(&before_lines, before_budget)?;
+            } else if after_budget > 0 {
+                after_budget = after_budget.saturating_sub(overshoot);
+                after = fit(&after_lines, after_budget)?;
+            } else {
+                break;
+            }
+        }
+        token_counts.prefix = before.token_count;
+        token_counts.suffix = after.token_count;
+        token_counts.total = fim_token_count + context_token_count;
+        let (before, after) = (before.text, after.text);
+        match &fim.template {
+            _ if layout == PromptLayout::Split => {
+                (format!("{}{before}", context.cross_file), Some(after))
+            }
+            _ if layout == PromptLayout::SplitSnippets => (before, Some(after)),
+            // without a placeholder for it, the context goes before the code as it does with
+            // the default layout
+            Some(template) if !template.contains("{context}") => (
+                render_fim_template(
+                    template,
+                    &format!("{}{before}", context.cross_file),
+                    &after,
+                    context,
+                ),
+                None,
+            ),
+            Some(template) => (
+                render_fim_template(template, &before, &after, context),
+                None,
+            ),
+            None => (
+                format!(
+                    "{}{}{before}{}{after}{}",
+                    fim.prefix, context.cross_file, fim.suffix, fim.middle
+                ),
+                None,
+            ),
+        }
+    } else {
+        let budget = context_window.saturating_sub(context_token_count);
+        let scopes = document
+            .tree
+            .as_ref()
+            .map(|tree| Scopes::new(tree, &document.text, pos));
+        let before_lines = Lines::collect(
+            document,
+            pos,
+            Side::Before,
+            tokenizer.as_ref(),
+            budget,
+            None,
+        )?;
+        let fit = |budget| {
+            before_lines.fit_scopes(
+                budget,
+                tokenizer.as_deref(),
+                scopes.as_ref(),
+                &document.text,
+            )
+        };
+        let mut before = fit(budget)?;
+        // the text kept is counted again as a whole, which may exceed the budget it was fitted to
+        let mut before_budget = budget;
+        while before.token_count > budget && before_budget > 0 {
+            before_budget = before_budget.saturating_sub(before.token_count - budget);
+            before = fit(before_budget)?;
+        }
+        token_counts.prefix = before.token_count;
+        token_counts.total = context_token_count;
+        (format!("{}{}", context.cross_file, before.text), None)
+    };
+    token_counts.total += token_counts.prefix + token_counts.suffix;
+    let time = t.elapsed().as_millis();
+    info!(
+        prompt,
+        suffix,
+        build_prompt_ms = time,
+        "built prompt in {time} ms"
+    );
+    let snippets = if fim.enabled && layout == PromptLayout::SplitSnippets {
+        context.snippets.clone()
+    } else {
+        vec![]
+    };
+    Ok(Prompt {
+        text: prompt,
+        suffix,
+        snippets,
+        token_counts,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use custom_types::llm_ls::LlmLsConfig;
+
+    use super::*;
+
+    fn context() -> PromptContext {
+        PromptContext {
+            cross_file: String::new(),
+            snippets: vec![],
+            filename: String::new(),
+            repo: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_prompt_with_template() {
+        let fim = FimParams {
+            template: Some(
+                "<repo_name>{repo}{context}<file_sep>{filename}\n<fim_prefix>{prefix}<fim_suffix>{suffix}<fim_middle>"
+                    .to_owned(),
+            ),
+            file_separator: Some("<file_sep>".to_owned()),
+            ..LlmLsConfig::default().fim
+        };
+        let context = PromptContext {
+            cross_file: "<file_sep>utils.py\ndef add(a, b): ...\n".to_owned(),
+            snippets: vec![],
+            filename: "src/main.py".to_owned(),
+            repo: "project".to_owned(),
+        };
+        let document = Document::open("python", "import utils\nprint({})\n")
+            .await
+            .unwrap();
+        let prompt = build_prompt(
+            Position::new(1, 6),
+            &document,
+            &fim,
+            &context,
+            None,
+            1024,
+            PromptLayout::Fim,
+        )
+        .unwrap()
+        .text;
+        assert_eq!(
+            prompt,
+            "<repo_name>project<file_sep>utils.py\ndef add(a, b): ...\n<file_sep>src/main.py\n<fim_prefix>import utils\nprint(<fim_suffix>{})\n<fim_middle>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_prompt_budget() {
+        let fim = LlmLsConfig::default().fim;
+        let document = Document::open("python", "aaaa\nbbbb\ncc\ndddd\neeee\n")
+            .await
+            .unwrap();
+        // 3 FIM tokens, 6 bytes before the cursor, then what fits in the remaining 6 bytes
+        let prompt = build_prompt(
+            Position::new(2, 1),
+            &document,
+            &fim,
+            &context(),
+            None,
+            15,
+            PromptLayout::Fim,
+        )
+        .unwrap();
+        assert_eq!(
+            prompt.text,
+            "<fim_prefix>bbbb\nc<fim_suffix>c\n<fim_middle>"
+        );
+        assert_eq!(
+            prompt.token_counts,
+            PromptTokenCounts {
+                prefix: 6,
+                suffix: 2,
+                context: 0,
+                total: 11,
+            }
+        );
+        let prompt = build_prompt(
+            Position::new(2, 1),
+            &document,
+            &fim,
+            &context(),
+            None,
+            1024,
+            PromptLayout::Fim,
+        )
+        .unwrap()
+        .text;
+        assert_eq!(
+            prompt,
+            "<fim_prefix>aaaa\nbbbb\nc<fim_suffix>c\ndddd\neeee\n<fim_middle>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_prompt_split_layout() {
+        let fim = LlmLsConfig::default().fim;
+        let document = Document::open("python", "aaaa\nbbbb\ncc\ndddd\neeee\n")
+            .await
+            .unwrap();
+        let with_cross_file = PromptContext {
+            cross_file: "# b.py\nx = 1\n".to_owned(),
+            ..context()
+        };
+        let prompt = build_prompt(
+            Position::new(2, 1),
+            &document,
+            &fim,
+            &with_cross_file,
+            None,
+            1024,
+            PromptLayout::Split,
+        )
+        .unwrap();
+        assert_eq!(prompt.text, "# b.py\nx = 1\naaaa\nbbbb\nc");
+        assert_eq!(prompt.suffix.as_deref(), Some("c\ndddd\neeee\n"));
+        assert_eq!(prompt.token_counts.total, 13 + 11 + 12);
+
+        let with_snippets = PromptContext {
+            snippets: vec![Snippet {
+                path: "b.py".to_owned(),
+                content: "x = 1\n".to_owned(),
+                score: 1.,
+            }],
+            ..with_cross_file
+        };
+        let prompt = build_prompt(
+            Position::new(2, 1),
+            &document,
+            &fim,
+            &with_snippets,
+            None,
+            1024,
+            PromptLayout::SplitSnippets,
+        )
+        .unwrap();
+        assert_eq!(prompt.text, "aaaa\nbbbb\nc");
+        assert_eq!(prompt.snippets.len(), 1);
+        assert_eq!(prompt.token_counts.context, 13);
+
+        // no FIM tokens to account for, the code gets the whole budget
+        let prompt = build_prompt(
+            Position::new(2, 1),
+            &document,
+            &fim,
+            &context(),
+            None,
+            12,
+            PromptLayout::Split,
+        )
+        .unwrap();
+        assert_eq!(prompt.text, "bbbb\nc");
+        assert_eq!(prompt.suffix.as_deref(), Some("c\n"));
+    }
+
+    #[tokio::test]
+    async fn test_build_prompt_budget_split() {
+        let document = Document::open("python", "aaaa\nbbbb\ncc\ndddd\neeee\n")
+            .await
+            .unwrap();
+        // the prefix gets the budget the suffix does not use
+        let fim = LlmLsConfig::default().fim;
+        let prompt = build_prompt(
+            Position::new(4, 0),
+            &document,
+            &fim,
+            &context(),
+            None,
+            23,
+            PromptLayout::Fim,
+        )
+        .unwrap()
+        .text;
+        assert_eq!(
+            prompt,
+            "<fim_prefix>bbbb\ncc\ndddd\n<fim_suffix>eeee\n<fim_middle>"
+        );
+
+        let fim = FimParams {
+            prefix_ratio: 0.75,
+            max_suffix_lines: Some(1),
+            ..LlmLsConfig::default().fim
+        };
+        let prompt = build_prompt(
+            Position::new(2, 1),
+            &document,
+            &fim,
+            &context(),
+            None,
+            1024,
+            PromptLayout::Fim,
+        )
+        .unwrap()
+        .text;
+        assert_eq!(
+            prompt,
+            "<fim_prefix>aaaa\nbbbb\nc<fim_suffix>c\ndddd\n<fim_middle>"
+        );
+        let prompt = build_prompt(
+            Position::new(2, 1),
+            &document,
+            &fim,
+            &context(),
+            None,
+            19,
+            PromptLayout::Fim,
+        )
+        .unwrap()
+        .text;
+        assert_eq!(
+            prompt,
+            "<fim_prefix>aaaa\nbbbb\nc<fim_suffix>c\n<fim_middle>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_prompt_fits_context_window() {
+        // merges a newline with the letter that follows, so that the lines kept are one token
+        // longer on their own than as part of the whole text
+        let tokenizer: Tokenizer = r#"{
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": null,
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "byte_fallback": false,
+                "vocab": {
+                    "a": 0, "b": 1, "c": 2, "d": 3, "e": 4, "f": 5, "g": 6, "h": 7, "i": 8,
+                    "j": 9, "\n": 10, "\na": 11
+                },
+                "merges": ["\n a"]
+            }
+        }"#
+        .parse()
+        .unwrap();
+        let tokenizer = Arc::new(tokenizer);
+        let document = Document::open("python", &"abcdefghij\n".repeat(100))
+            .await
+            .unwrap();
+        for enabled in [true, false] {
+            let fim = FimParams {
+                enabled,
+                ..LlmLsConfig::default().fim
+            };
+            for context_window in 400..600 {
+                let prompt = build_prompt(
+                    Position::new(60, 10),
+                    &document,
+                    &fim,
+                    &context(),
+                    Some(tokenizer.clone()),
+                    context_window,
+                    PromptLayout::Fim,
+                )
+                .unwrap();
+                let counts = prompt.token_counts;
+                assert!(
+                    counts.total <= context_window,
+                    "{counts:?} exceeds {context_window}"
+                );
+                let before = prompt.text.split("<fim_suffix>").next().unwrap();
+                let before = before.trim_start_matches("<fim_prefix>");
+                assert_eq!(
+                    counts.prefix,
+                    tokenizer.encode(before, false).unwrap().len()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_prompt_keeps_scopes() {
+        let fim = FimParams {
+            enabled: false,
+            ..LlmLsConfig::default().fim
+        };
+        let document = Document::open(
+            "python",
+            r#"import os, sys
+class Foo:
+    def bar(self):
+        x = 1
+        y = 2
+        return x + y
+
+    def baz(self):
+        a = 1
+        b = 2
+        c = 3
+        return a
+"#,
+        )
+        .await
+        .unwrap();
+        let prompt = build_prompt(
+            Position::new(11, 8),
+            &document,
+            &fim,
+            &context(),
+            None,
+            100,
+            PromptLayout::Fim,
+        )
+        .unwrap()
+        .text;
+        assert_eq!(
+            prompt,
+            r#"import os, sys
+class Foo:
+    def baz(self):
+        a = 1
+        b = 2
+        c = 3
+        "#
+        );
+    }
+}
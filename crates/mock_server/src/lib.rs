@@ -0,0 +1,233 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use std::{collections::HashMap, net::TcpListener, sync::Arc};
+use tokio::{
+    sync::Mutex,
+    time::{sleep, Duration},
+};
+
+#[derive(Clone)]
+struct AppState {
+    counter: Arc<Mutex<u32>>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct GeneratedText {
+    generated_text: String,
+}
+
+async fn default(state: State<AppState>) -> Json<Vec<GeneratedText>> {
+    let mut lock = state.counter.lock().await;
+    *lock += 1;
+    println!("got request {}", lock);
+    Json(vec![GeneratedText {
+        generated_text: "dummy".to_owned(),
+    }])
+}
+
+async fn tgi(state: State<AppState>) -> Json<GeneratedText> {
+    let mut lock = state.counter.lock().await;
+    *lock += 1;
+    Json(GeneratedText {
+        generated_text: "dummy".to_owned(),
+    })
+}
+
+async fn log_headers(headers: HeaderMap, state: State<AppState>) -> Json<GeneratedText> {
+    let mut lock = state.counter.lock().await;
+    *lock += 1;
+    for (name, value) in headers.iter() {
+        println!("{lock} - {}: {}", name, value.to_str().unwrap());
+    }
+    Json(GeneratedText {
+        generated_text: "dummy".to_owned(),
+    })
+}
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+/// Stand-in for the inference API failing every other request, e.g. when overloaded.
+async fn flaky(state: State<AppState>) -> Response {
+    let mut lock = state.counter.lock().await;
+    *lock += 1;
+    if *lock % 2 == 0 {
+        let error = ApiError {
+            error: "Model is overloaded".to_owned(),
+        };
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(error)).into_response();
+    }
+    Json(vec![GeneratedText {
+        generated_text: "dummy".to_owned(),
+    }])
+    .into_response()
+}
+
+async fn wait(state: State<AppState>) -> Json<GeneratedText> {
+    let mut lock = state.counter.lock().await;
+    *lock += 1;
+    sleep(Duration::from_millis(200)).await;
+    println!("waited for req {}", lock);
+    Json(GeneratedText {
+        generated_text: "dummy".to_owned(),
+    })
+}
+
+#[derive(Deserialize, Serialize)]
+struct AnthropicMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct AnthropicRequest {
+    model: Option<String>,
+    max_tokens: Option<u32>,
+    #[serde(default)]
+    messages: Vec<AnthropicMessage>,
+    #[serde(flatten)]
+    other: HashMap<String, IgnoredAny>,
+}
+
+/// Optional fields of the Messages API, any other field being rejected
+const ANTHROPIC_OPTIONAL_FIELDS: [&str; 7] = [
+    "metadata",
+    "stop_sequences",
+    "stream",
+    "system",
+    "temperature",
+    "top_k",
+    "top_p",
+];
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicContentBlock {
+    Text { text: String },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicResponse {
+    Message {
+        role: String,
+        model: String,
+        content: Vec<AnthropicContentBlock>,
+        stop_reason: String,
+    },
+    Error {
+        error: AnthropicError,
+    },
+}
+
+#[derive(Serialize)]
+struct AnthropicError {
+    r#type: String,
+    message: String,
+}
+
+fn anthropic_error(status: StatusCode, r#type: &str, message: &str) -> Response {
+    let error = AnthropicResponse::Error {
+        error: AnthropicError {
+            r#type: r#type.to_owned(),
+            message: message.to_owned(),
+        },
+    };
+    (status, Json(error)).into_response()
+}
+
+/// Stand-in for Anthropic's Messages API, validating the headers and fields it requires.
+async fn anthropic_messages(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(req): Json<AnthropicRequest>,
+) -> Response {
+    let mut lock = state.counter.lock().await;
+    *lock += 1;
+    if !headers.contains_key("x-api-key") {
+        return anthropic_error(
+            StatusCode::UNAUTHORIZED,
+            "authentication_error",
+            "x-api-key header is required",
+        );
+    }
+    if !headers.contains_key("anthropic-version") {
+        return anthropic_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "anthropic-version: header is required",
+        );
+    }
+    let Some(model) = req.model else {
+        return anthropic_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "model: Field required",
+        );
+    };
+    if req.max_tokens.is_none() {
+        return anthropic_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "max_tokens: Field required",
+        );
+    }
+    if let Some(field) = req
+        .other
+        .keys()
+        .find(|field| !ANTHROPIC_OPTIONAL_FIELDS.contains(&field.as_str()))
+    {
+        return anthropic_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            &format!("{field}: Extra inputs are not permitted"),
+        );
+    }
+    if req.messages.last().map(|message| message.role.as_str()) != Some("user") {
+        return anthropic_error(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "messages: the last message must have the user role",
+        );
+    }
+    Json(AnthropicResponse::Message {
+        role: "assistant".to_owned(),
+        model,
+        content: vec![AnthropicContentBlock::Text {
+            text: "dummy".to_owned(),
+        }],
+        stop_reason: "end_turn".to_owned(),
+    })
+    .into_response()
+}
+
+pub fn app() -> Router {
+    let app_state = AppState {
+        counter: Arc::new(Mutex::new(0)),
+    };
+    Router::new()
+        .route("/", post(default))
+        .route("/tgi", post(tgi))
+        .route("/headers", post(log_headers))
+        .route("/wait", post(wait))
+        .route("/models/flaky", post(flaky))
+        .route("/v1/messages", post(anthropic_messages))
+        .with_state(app_state)
+}
+
+/// Serves the mock routes on an already bound listener, e.g. on a random port from tests.
+pub async fn serve(listener: TcpListener) {
+    axum::Server::from_tcp(listener)
+        .expect("listener to be usable")
+        .serve(app().into_make_service())
+        .await
+        .expect("server to start");
+}
@@ -11,15 +11,17 @@ use std::{
 use anyhow::anyhow;
 use clap::Parser;
 use custom_types::{
-    llm_ls::{Backend, FimParams, GetCompletionsParams, Ide, TokenizerConfig},
+    llm_ls::{
+        Backend, FimParams, GetCompletionsParams, Ide, LlmLsConfigOverrides, TokenizerConfig,
+    },
     request::GetCompletions,
 };
 use futures_util::{stream::FuturesUnordered, StreamExt, TryStreamExt};
 use lang::Language;
 use lsp_client::{client::LspClient, error::ExtractError, server::Server};
 use lsp_types::{
-    DidOpenTextDocumentParams, InitializeParams, TextDocumentIdentifier, TextDocumentItem,
-    TextDocumentPositionParams,
+    DidOpenTextDocumentParams, InitializeParams, PartialResultParams, TextDocumentIdentifier,
+    TextDocumentItem, TextDocumentPositionParams,
 };
 use ropey::Rope;
 use runner::Runner;
@@ -314,9 +316,7 @@ async fn download_repo_from_github(
     .await?
     .error_for_status()?
     .bytes_stream();
-    let stream = stream
-        .map_err(|e| futures::io::Error::new(futures::io::ErrorKind::Other, e))
-        .into_async_read();
+    let stream = stream.map_err(futures::io::Error::other).into_async_read();
     let mut stream = stream.compat();
     io::copy(&mut stream, &mut archive).await?;
     let archive = BufReader::new(std::fs::File::open(archive_path)?);
@@ -543,25 +543,36 @@ async fn complete_holes(
         );
         let result = client
             .send_request::<GetCompletions>(GetCompletionsParams {
-                api_token: api_token.clone(),
-                context_window,
-                fim: fim.clone(),
                 ide: Ide::default(),
-                model: model.clone(),
-                backend,
                 text_document_position: TextDocumentPositionParams {
                     position: hole.cursor,
                     text_document: TextDocumentIdentifier { uri },
                 },
-                tls_skip_verify_insecure,
-                tokens_to_clear: tokens_to_clear.clone(),
-                tokenizer_config: tokenizer_config.clone(),
-                request_body: request_body.clone(),
-                disable_url_path_completion,
+                partial_result_params: PartialResultParams::default(),
+                config: LlmLsConfigOverrides {
+                    api_token: api_token.clone(),
+                    context_window: Some(context_window),
+                    fim: Some(fim.clone()),
+                    model: Some(model.clone()),
+                    backend: Some(backend),
+                    tls_skip_verify_insecure: Some(tls_skip_verify_insecure),
+                    tokens_to_clear: Some(tokens_to_clear.clone()),
+                    tokenizer_config: tokenizer_config.clone(),
+                    request_body: Some(request_body.clone()),
+                    disable_url_path_completion: Some(disable_url_path_completion),
+                    ..Default::default()
+                },
             })
             .await?;
 
-        file_content.insert(hole_start, &result.completions[0].generated_text);
+        let Some(completion) = result.completions.first() else {
+            return Err(anyhow!(
+                "no completion for hole {}: {:?}",
+                hole.cursor.line,
+                result.skip_reason
+            ));
+        };
+        file_content.insert(hole_start, &completion.generated_text);
         let mut file = OpenOptions::new()
             .write(true)
             .truncate(true)
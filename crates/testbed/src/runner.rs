@@ -56,8 +56,8 @@ async fn pytest_runner(
 
     // XXX: the pytest command can still fail even after the compilation check
     // the above check should prevent an error, but better safe than sorry
-    let lines = stdout.split_terminator('\n');
-    let result = match lines.last() {
+    let mut lines = stdout.split_terminator('\n');
+    let result = match lines.next_back() {
         Some(line) => line.replace('=', "").trim().to_owned(),
         None => return Ok(0f32),
     };